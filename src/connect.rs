@@ -1,9 +1,18 @@
-use crate::{bench, util::parse_interface, BenchOpts, Error};
+use crate::{
+    bench, close, rate, to_mb, util::parse_interface, BenchOpts, BenchResult, ConnectStats,
+    ConnectionOpts, Error, IdleStats, PingStats, Shutdown,
+};
 use clap::Parser;
-use futures_util::{StreamExt, TryStreamExt};
+use futures_util::{future::pending, SinkExt, StreamExt};
+use parking_lot::Mutex;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time,
+    time::{Duration, Interval, MissedTickBehavior},
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 use url::Url;
 
 /// Connection options
@@ -29,16 +38,35 @@ pub struct ConnectOpts {
     #[arg(short = 't', long, default_value = "0", value_name = "NUM")]
     pub threads: usize,
 
+    /// Stop the benchmark after second, ignore when set to 0
+    #[arg(short = 'd', long, default_value = "0", value_name = "NUM")]
+    pub duration: u64,
+
+    /// Exclude the first seconds from measurements, ignore when set to 0
+    #[arg(short = 'w', long, default_value = "0", value_name = "NUM")]
+    pub warmup: u64,
+
+    /// Send websocket ping every second and measure the pong round trip time, ignore when set to 0
+    #[arg(long, default_value = "0", value_name = "NUM")]
+    pub ping: u64,
+
+    /// Probe the relay idle timeout, send nothing and record server pings and connection lifetimes
+    #[arg(long, conflicts_with = "ping")]
+    pub idle: bool,
+
     /// Network interface address list
     #[arg(short = 'i', long, value_name = "IP", value_parser = parse_interface)]
     pub interface: Option<Vec<SocketAddr>>,
 
+    #[command(flatten)]
+    pub connection: ConnectionOpts,
+
     /// Display stats information as json, time format as milli seconds
     #[arg(long)]
     pub json: bool,
 }
 
-pub async fn start(opts: ConnectOpts) {
+pub async fn start(opts: ConnectOpts) -> Result<BenchResult, Error> {
     let bench_opts = BenchOpts {
         url: opts.url,
         count: opts.count,
@@ -46,40 +74,353 @@ pub async fn start(opts: ConnectOpts) {
         keepalive: opts.keepalive,
         threads: opts.threads,
         interface: opts.interface,
+        duration: opts.duration,
+        warmup: opts.warmup,
+        connection: opts.connection,
+    };
+    let json = opts.json;
+    let warmup = opts.warmup;
+    let probe = Probe {
+        ping: (opts.ping > 0).then(|| Duration::from_secs(opts.ping)),
+        idle: opts.idle,
+        ping_stats: Default::default(),
+        idle_stats: Default::default(),
+    };
+    let w_probe = probe.clone();
+    let on_warmup = move |_: &ConnectStats| {
+        *w_probe.ping_stats.lock() = PingStats::default();
+        *w_probe.idle_stats.lock() = IdleStats::default();
     };
-    bench(
-        bench_opts,
-        |stream| wait(stream),
-        move |now, stats| {
-            if opts.json {
-                let json = serde_json::json!({
-                    "elapsed": now.elapsed().as_millis(),
-                    "connect_stats": stats,
-                });
-                println!("{}", serde_json::to_string(&json).unwrap());
-            } else {
-                let time = stats.success_time;
-                let time = format!(
-                    "avg: {}ms max: {}ms min: {}ms",
-                    time.avg.as_millis(),
-                    time.max.as_millis(),
-                    time.min.as_millis(),
-                );
-                println!(
-                    "elapsed: {}ms connections: {} error: {} connect time: [{}] ",
-                    now.elapsed().as_millis(),
-                    stats.alive,
-                    stats.error,
-                    time,
-                );
+    let c_probe = probe.clone();
+    let p_probe = probe.clone();
+    let mut last_wire = (0, 0);
+    let mut last_time = time::Instant::now();
+    let handler = move |stream, shutdown| wait(stream, c_probe, shutdown);
+    let printer = move |now: time::Instant, stats: &ConnectStats| {
+        let in_warmup = now.elapsed().as_secs() < warmup;
+        let sent = rate(stats.sent - last_wire.0, last_time.elapsed());
+        let received = rate(stats.received - last_wire.1, last_time.elapsed());
+        last_wire = (stats.sent, stats.received);
+        last_time = time::Instant::now();
+        if opts.json {
+            let json = serde_json::json!({
+                "elapsed": now.elapsed().as_millis(),
+                "warmup": in_warmup,
+                "wire_sent_mb": sent,
+                "wire_received_mb": received,
+                "connect_stats": stats,
+                "window_phase": stats.window_phase,
+                "ping_stats": p_probe.ping.map(|_| p_probe.ping_stats.lock().clone()),
+                "idle_stats": p_probe.idle.then(|| p_probe.idle_stats.lock().clone()),
+            });
+            println!("{}", serde_json::to_string(&json).unwrap());
+        } else {
+            println!(
+                "{}elapsed: {}ms connections: {} error: {} timeout: {}/{} connect time: [{}] phase: [{}] wire: [sent: {}MB/s received: {}MB/s]",
+                if in_warmup { "warmup " } else { "" },
+                now.elapsed().as_millis(),
+                stats.alive,
+                stats.error,
+                stats.errors.connect_timeout,
+                stats.errors.handshake_timeout,
+                stats.success_time,
+                stats.window_phase,
+                sent,
+                received,
+            );
+            if p_probe.ping.is_some() {
+                println!("ping {}", p_probe.ping_stats.lock());
+            }
+            if p_probe.idle {
+                println!("idle {}", p_probe.idle_stats.lock());
+            }
+        }
+    };
+    let result = bench(bench_opts, handler, printer, on_warmup).await?;
+
+    let stats = &result.stats;
+    if json {
+        let json = serde_json::json!({
+            "summary": {
+                "elapsed": result.elapsed.as_millis(),
+                "interrupted": result.interrupted,
+                "connect_stats": stats,
+                "ping_stats": probe.ping.map(|_| probe.ping_stats.lock().clone()),
+                "idle_stats": probe.idle.then(|| probe.idle_stats.lock().clone()),
+                "warmup": result.warmup,
+            }
+        });
+        println!("{}", serde_json::to_string(&json).unwrap());
+    } else {
+        if let Some(wstats) = &result.warmup {
+            println!(
+                "warmup elapsed: {}ms connections: {} error: {} connect time: [{}]",
+                Duration::from_secs(warmup).as_millis(),
+                wstats.connect,
+                wstats.error,
+                wstats.success_time,
+            );
+        }
+        println!(
+            "summary elapsed: {}ms interrupted: {} connections: {} error: {} connect timeout: {} handshake timeout: {} connect time: [{}]",
+            result.elapsed.as_millis(),
+            result.interrupted,
+            stats.connect,
+            stats.error,
+            stats.errors.connect_timeout,
+            stats.errors.handshake_timeout,
+            stats.success_time,
+        );
+        println!("summary end {}", stats.ends());
+        if stats.reconnect > 0 || stats.reconnect_error > 0 {
+            println!(
+                "summary reconnect: {} error: {} time: [{}]",
+                stats.reconnect, stats.reconnect_error, stats.reconnect_time
+            );
+        }
+        if stats.errors.total() > 0 {
+            println!("summary errors: [{}]", stats.errors);
+        }
+        for (name, time) in stats.phase.iter() {
+            println!("summary {} time: [{}]", name, time);
+        }
+        if stats.deflate > 0 {
+            println!("summary deflate connections: {}", stats.deflate);
+        }
+        if probe.ping.is_some() {
+            println!("summary ping {}", probe.ping_stats.lock());
+        }
+        if probe.idle {
+            println!("summary idle {}", probe.idle_stats.lock());
+        }
+        let (sent, received) = result.wire();
+        let elapsed = result.measured(warmup).as_secs_f64();
+        println!(
+            "summary wire sent: {}MB {}MB/s received: {}MB {}MB/s",
+            to_mb(sent as f64),
+            to_mb(sent as f64 / elapsed),
+            to_mb(received as f64),
+            to_mb(received as f64 / elapsed),
+        );
+    }
+    Ok(result)
+}
+
+/// Ping and idle probe of the connections
+#[derive(Clone)]
+struct Probe {
+    /// ping interval, none when disabled
+    ping: Option<Duration>,
+    /// record server pings and lifetimes
+    idle: bool,
+    ping_stats: Arc<Mutex<PingStats>>,
+    idle_stats: Arc<Mutex<IdleStats>>,
+}
+
+/// Wait until closed, record the lifetime when not closed by the benchmark
+async fn wait<S>(
+    stream: WebSocketStream<S>,
+    probe: Probe,
+    mut shutdown: Shutdown,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = time::Instant::now();
+    let res = wait_closed(stream, &probe, &mut shutdown).await;
+    if probe.idle && !shutdown.is_shutdown() {
+        probe.idle_stats.lock().lifetime.add(start.elapsed());
+    }
+    res
+}
+
+/// Send ping at the interval and match the pong by sequence, answer the server ping,
+/// the ping still waiting for pong when the connection ends is missing
+async fn wait_closed<S>(
+    stream: WebSocketStream<S>,
+    probe: &Probe,
+    shutdown: &mut Shutdown,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut write, mut read) = stream.split();
+    let stats = &probe.ping_stats;
+    let mut pinged = false;
+    let mut ticker = probe.ping.map(|period| {
+        let mut ticker = time::interval_at(time::Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+    // sequence and send time of the ping waiting for pong
+    let mut waiting: Option<(u64, time::Instant)> = None;
+    let mut seq: u64 = 0;
+    let res = async {
+        loop {
+            tokio::select! {
+                msg = read.next() => match msg {
+                    Some(msg) => match msg? {
+                        Message::Close(frame) => return Err(Error::Closed(frame)),
+                        Message::Pong(payload) => match waiting {
+                            Some((id, start)) if payload == id.to_be_bytes() => {
+                                waiting = None;
+                                stats.lock().add_pong(start.elapsed());
+                            }
+                            // late pong of the missing ping or unsolicited pong
+                            _ => {}
+                        },
+                        Message::Ping(_) if probe.idle => {
+                            {
+                                let mut r = probe.idle_stats.lock();
+                                r.ping += 1;
+                                if !pinged {
+                                    pinged = true;
+                                    r.pinged += 1;
+                                }
+                            }
+                            // send the pong queued by the websocket library
+                            write.flush().await?;
+                        }
+                        _ => {}
+                    },
+                    None => return Ok(()),
+                },
+                _ = tick(&mut ticker) => {
+                    {
+                        let mut r = stats.lock();
+                        if waiting.take().is_some() {
+                            r.missing += 1;
+                        }
+                        r.sent += 1;
+                    }
+                    seq += 1;
+                    waiting = Some((seq, time::Instant::now()));
+                    write.send(Message::Ping(seq.to_be_bytes().to_vec())).await?;
+                }
+                _ = shutdown.wait() => return close(&mut write, &mut read).await,
             }
-        },
-    )
+        }
+    }
     .await;
+    if waiting.is_some() {
+        stats.lock().missing += 1;
+    }
+    res
+}
+
+/// Wait for the next tick, never complete without ticker
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => pending::<()>().await,
+    }
 }
 
-async fn wait(stream: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Result<(), Error> {
-    let (_write, read) = stream.split();
-    read.try_for_each(|_message| async { Ok(()) }).await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::{wait, Probe};
+    use crate::{ConnectStats, Shutdown};
+    use std::future::Future;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::sync::watch;
+    use tokio_tungstenite::{tungstenite::protocol::Role, WebSocketStream};
+
+    fn probe(ping: Option<Duration>, idle: bool) -> Probe {
+        Probe {
+            ping,
+            idle,
+            ping_stats: Default::default(),
+            idle_stats: Default::default(),
+        }
+    }
+
+    /// Run the client against the server script, return how the connection ended
+    fn run<F, Fut>(probe: &Probe, server: F) -> ConnectStats
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (client, server_stream) = tokio::io::duplex(1024);
+            let server = tokio::spawn(server(server_stream));
+            let (_tx, rx) = watch::channel(false);
+            let stream = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let res = wait(stream, probe.clone(), Shutdown(rx)).await;
+            server.await.unwrap();
+            let mut stats = ConnectStats::default();
+            stats.add_end(&res, false);
+            stats
+        })
+    }
+
+    /// Read a masked client frame, return the opcode and payload
+    async fn read_frame(stream: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 6];
+        stream.read_exact(&mut header).await.unwrap();
+        let mut payload = vec![0; (header[1] & 0x7f) as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= header[2 + i % 4];
+        }
+        (header[0] & 0x0f, payload)
+    }
+
+    async fn write_frame(stream: &mut DuplexStream, opcode: u8, payload: &[u8]) {
+        let mut frame = vec![0x80 | opcode, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    #[test]
+    fn ping_pong() {
+        let probe = probe(Some(Duration::from_millis(50)), false);
+        let res = run(&probe, |mut server| async move {
+            // answered
+            let (opcode, first) = read_frame(&mut server).await;
+            assert_eq!((opcode, first.as_slice()), (9, &1u64.to_be_bytes()[..]));
+            write_frame(&mut server, 10, &first).await;
+            // missing, the pong arrives after the next ping
+            let (_, second) = read_frame(&mut server).await;
+            let (_, third) = read_frame(&mut server).await;
+            assert_eq!(third, 3u64.to_be_bytes());
+            write_frame(&mut server, 10, &second).await;
+            write_frame(&mut server, 10, b"unsolicited").await;
+            write_frame(&mut server, 10, &third).await;
+            // still waiting at close
+            read_frame(&mut server).await;
+            write_frame(&mut server, 8, &1001u16.to_be_bytes()).await;
+        });
+        assert_eq!(res.server_close, 1);
+        let stats = probe.ping_stats.lock();
+        assert_eq!(stats.sent, 4);
+        assert_eq!(stats.pong, 2);
+        assert_eq!(stats.missing, 2);
+        assert_eq!(stats.rtt.count, 2);
+    }
+
+    #[test]
+    fn idle() {
+        let probe = probe(None, true);
+        let res = run(&probe, |mut server| async move {
+            write_frame(&mut server, 9, b"keepalive").await;
+            // the server ping is answered
+            assert_eq!(read_frame(&mut server).await, (10, b"keepalive".to_vec()));
+            write_frame(&mut server, 9, b"keepalive").await;
+            assert_eq!(read_frame(&mut server).await.0, 10);
+            write_frame(&mut server, 8, &1001u16.to_be_bytes()).await;
+        });
+        assert_eq!(res.server_close, 1);
+        assert_eq!(res.close_reasons.get("1001"), Some(&1));
+        let stats = probe.idle_stats.lock();
+        assert_eq!(stats.ping, 2);
+        assert_eq!(stats.pinged, 1);
+        assert_eq!(stats.lifetime.count, 1);
+    }
 }
@@ -0,0 +1,824 @@
+use crate::stream::Traffic;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io::{self, Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Tail of the sync flushed deflate block, removed from the message payload
+const DEFLATE_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+/// Max size of the http upgrade response header
+const MAX_HEADER_SIZE: usize = 65536;
+
+/// Size of the buffer reading from the inner stream
+const READ_SIZE: usize = 16384;
+
+/// Max payload size of a frame and of a decompressed message, as the websocket library default
+const MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// permessage-deflate parameters offered in the handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deflate {
+    /// max window bits for both sides, 15 when not set
+    pub window_bits: Option<u8>,
+    /// reset the compression context after each message for both sides
+    pub no_context_takeover: bool,
+}
+
+impl Deflate {
+    /// Sec-WebSocket-Extensions header value of the offer
+    pub fn offer(&self) -> String {
+        let mut offer = "permessage-deflate".to_owned();
+        match self.window_bits {
+            Some(bits) => offer.push_str(&format!(
+                "; client_max_window_bits={0}; server_max_window_bits={0}",
+                bits
+            )),
+            None => offer.push_str("; client_max_window_bits"),
+        }
+        if self.no_context_takeover {
+            offer.push_str("; client_no_context_takeover; server_no_context_takeover");
+        }
+        offer
+    }
+}
+
+/// permessage-deflate parameters accepted by the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Params {
+    pub client_max_window_bits: Option<u8>,
+    pub server_max_window_bits: Option<u8>,
+    pub client_no_context_takeover: bool,
+    pub server_no_context_takeover: bool,
+}
+
+/// Parse the permessage-deflate parameters from the upgrade response header,
+/// none when the server did not accept the extension
+pub fn parse_response(header: &[u8]) -> io::Result<Option<Params>> {
+    let header = String::from_utf8_lossy(header);
+    let mut lines = header.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Ok(None);
+    }
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("sec-websocket-extensions") => {
+                (name, value)
+            }
+            _ => continue,
+        };
+        for extension in value.split(',') {
+            let mut params = extension.split(';').map(str::trim);
+            if params.next() != Some("permessage-deflate") {
+                continue;
+            }
+            return parse_params(params)
+                .map(Some)
+                .map_err(|e| invalid(format!("{}:{}: {}", name, value, e)));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_params<'a>(params: impl Iterator<Item = &'a str>) -> Result<Params, String> {
+    let mut res = Params::default();
+    for param in params.filter(|param| !param.is_empty()) {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        let bits = || match value.map(str::parse::<u8>) {
+            Some(Ok(bits)) if (9..=15).contains(&bits) => Ok(bits),
+            _ => Err(format!("unsupported {}", param)),
+        };
+        match name {
+            "client_max_window_bits" => res.client_max_window_bits = Some(bits()?),
+            "server_max_window_bits" => res.server_max_window_bits = Some(bits()?),
+            "client_no_context_takeover" => res.client_no_context_takeover = true,
+            "server_no_context_takeover" => res.server_no_context_takeover = true,
+            _ => return Err(format!("unknown parameter {}", param)),
+        }
+    }
+    Ok(res)
+}
+
+/// Compression context of a negotiated connection
+struct Codec {
+    compress: Compress,
+    decompress: Decompress,
+    /// reset the compression context after each sent message
+    compress_reset: bool,
+    /// reset the decompression context after each received message
+    decompress_reset: bool,
+}
+
+impl Codec {
+    fn new(offer: &Deflate, params: Params) -> Self {
+        let bits = params
+            .client_max_window_bits
+            .or(offer.window_bits)
+            .unwrap_or(15);
+        Self {
+            compress: Compress::new_with_window_bits(Compression::default(), false, bits),
+            // a larger window decodes any smaller one
+            decompress: Decompress::new_with_window_bits(false, 15),
+            // the client offering no context takeover does not use it even when not echoed
+            compress_reset: offer.no_context_takeover || params.client_no_context_takeover,
+            decompress_reset: params.server_no_context_takeover,
+        }
+    }
+
+    /// Compress the message payload, the sync flush tail is removed
+    fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let mut pos = 0;
+        loop {
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(1024));
+            }
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(&data[pos..], &mut out, FlushCompress::Sync)
+                .map_err(|e| invalid(e.to_string()))?;
+            pos += (self.compress.total_in() - before) as usize;
+            if pos == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.compress_reset {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    /// Decompress the message payload without the sync flush tail
+    fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut input = Vec::with_capacity(data.len() + DEFLATE_TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity((data.len() * 4 + 64).min(MAX_FRAME_SIZE));
+        let mut pos = 0;
+        loop {
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(1024));
+            }
+            let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(&input[pos..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| invalid(e.to_string()))?;
+            pos += (self.decompress.total_in() - before_in) as usize;
+            if out.len() > MAX_FRAME_SIZE {
+                return Err(invalid("decompressed message too large".to_owned()));
+            }
+            if pos == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            let progress = self.decompress.total_in() != before_in
+                || self.decompress.total_out() != before_out;
+            if status == Status::StreamEnd || !progress && out.len() < out.capacity() {
+                return Err(invalid("invalid compressed message".to_owned()));
+            }
+        }
+        if self.decompress_reset {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+/// Websocket frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// length of the header
+    len: usize,
+    /// length of the payload
+    payload: usize,
+}
+
+impl Header {
+    /// Parse frame header, none when incomplete
+    fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 2 {
+            return None;
+        }
+        let masked = buf[1] & 0x80 != 0;
+        let (payload, mut len) = match buf[1] & 0x7f {
+            126 => (
+                u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize,
+                4,
+            ),
+            127 => (
+                u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?) as usize,
+                10,
+            ),
+            payload => (payload as usize, 2),
+        };
+        let mask = if masked {
+            let mask = buf.get(len..len + 4)?.try_into().ok()?;
+            len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Some(Self {
+            fin: buf[0] & 0x80 != 0,
+            rsv1: buf[0] & 0x40 != 0,
+            opcode: buf[0] & 0x0f,
+            mask,
+            len,
+            payload,
+        })
+    }
+
+    fn is_control(&self) -> bool {
+        self.opcode & 0x08 != 0
+    }
+
+    /// End of the frame starting at the position, error when the payload is too large
+    fn end(&self, pos: usize) -> io::Result<usize> {
+        if self.payload > MAX_FRAME_SIZE {
+            return Err(invalid(format!("frame too large: {}", self.payload)));
+        }
+        pos.checked_add(self.len + self.payload)
+            .ok_or_else(|| invalid("frame too large".to_owned()))
+    }
+
+    /// Write the frame with the payload, masked when the mask is set
+    fn write(&self, out: &mut Vec<u8>, payload: &[u8]) {
+        let mut b0 = self.opcode;
+        if self.fin {
+            b0 |= 0x80;
+        }
+        if self.rsv1 {
+            b0 |= 0x40;
+        }
+        out.push(b0);
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            len if len < 126 => out.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match self.mask {
+            Some(mask) => {
+                out.extend_from_slice(&mask);
+                out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            }
+            None => out.extend_from_slice(payload),
+        }
+    }
+}
+
+/// Apply or remove the websocket mask
+fn unmask(payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    match mask {
+        Some(mask) => payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect(),
+        None => payload.to_vec(),
+    }
+}
+
+/// Stream layer between the websocket and the transport implementing permessage-deflate,
+/// the compressed frames are rewritten as plain frames for the websocket library,
+/// the layer passes all bytes through when the extension is not negotiated
+pub struct DeflateStream<S> {
+    inner: S,
+    offer: Option<Deflate>,
+    codec: Option<Codec>,
+    traffic: Arc<Traffic>,
+    /// reading the upgrade response header
+    read_handshake: bool,
+    /// writing the upgrade request header
+    write_handshake: bool,
+    /// bytes read from the inner stream not processed
+    read_in: Vec<u8>,
+    /// processed bytes to return, and the position returned
+    read_out: Vec<u8>,
+    read_pos: usize,
+    /// compressed fragments of the message being received, with the opcode
+    fragments: Option<(u8, Vec<u8>)>,
+    /// bytes written by the websocket not processed
+    write_in: Vec<u8>,
+    /// processed bytes to write to the inner stream, and the position written
+    write_out: Vec<u8>,
+    write_pos: usize,
+    /// sending an uncompressed fragmented message
+    write_fragmented: bool,
+}
+
+impl<S> DeflateStream<S> {
+    /// Wrap the stream, offer permessage-deflate when set
+    pub fn new(inner: S, offer: Option<Deflate>, traffic: Arc<Traffic>) -> Self {
+        Self {
+            inner,
+            offer,
+            codec: None,
+            traffic,
+            read_handshake: offer.is_some(),
+            write_handshake: offer.is_some(),
+            read_in: vec![],
+            read_out: vec![],
+            read_pos: 0,
+            fragments: None,
+            write_in: vec![],
+            write_out: vec![],
+            write_pos: 0,
+            write_fragmented: false,
+        }
+    }
+
+    /// Whether permessage-deflate is negotiated
+    pub fn is_negotiated(&self) -> bool {
+        self.codec.is_some()
+    }
+
+    /// Move the processed bytes of the read input to the output
+    fn process_read(&mut self) -> io::Result<()> {
+        if self.read_handshake {
+            let end = match self.read_in.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(pos) => pos + 4,
+                None if self.read_in.len() > MAX_HEADER_SIZE => {
+                    return Err(invalid("upgrade response too large".to_owned()))
+                }
+                None => return Ok(()),
+            };
+            if let (Some(offer), Some(params)) =
+                (&self.offer, parse_response(&self.read_in[..end])?)
+            {
+                self.codec = Some(Codec::new(offer, params));
+            }
+            self.read_out.extend(self.read_in.drain(..end));
+            self.read_handshake = false;
+        }
+        let codec = match &mut self.codec {
+            Some(codec) => codec,
+            None => {
+                self.read_out.append(&mut self.read_in);
+                return Ok(());
+            }
+        };
+        let mut pos = 0;
+        while let Some(header) = Header::parse(&self.read_in[pos..]) {
+            let end = header.end(pos)?;
+            if self.read_in.len() < end {
+                break;
+            }
+            let frame = &self.read_in[pos..end];
+            let payload = &frame[header.len..];
+            if header.is_control() {
+                self.read_out.extend_from_slice(frame);
+            } else if let Some((opcode, fragments)) = &mut self.fragments {
+                if fragments.len() + header.payload > MAX_FRAME_SIZE {
+                    return Err(invalid("compressed message too large".to_owned()));
+                }
+                fragments.extend(unmask(payload, header.mask));
+                self.traffic.add_compressed_received(header.payload, 0);
+                if header.fin {
+                    let data = codec.decompress(fragments)?;
+                    self.traffic.add_compressed_received(0, data.len());
+                    let header = Header {
+                        fin: true,
+                        rsv1: false,
+                        opcode: *opcode,
+                        mask: None,
+                        len: 0,
+                        payload: 0,
+                    };
+                    header.write(&mut self.read_out, &data);
+                    self.fragments = None;
+                }
+            } else if header.rsv1 && header.opcode != 0 {
+                let data = unmask(payload, header.mask);
+                if header.fin {
+                    let data = codec.decompress(&data)?;
+                    self.traffic
+                        .add_compressed_received(header.payload, data.len());
+                    let header = Header {
+                        rsv1: false,
+                        mask: None,
+                        ..header
+                    };
+                    header.write(&mut self.read_out, &data);
+                } else {
+                    self.traffic.add_compressed_received(header.payload, 0);
+                    self.fragments = Some((header.opcode, data));
+                }
+            } else {
+                self.traffic
+                    .add_compressed_received(header.payload, header.payload);
+                self.read_out.extend_from_slice(frame);
+            }
+            pos = end;
+        }
+        self.read_in.drain(..pos);
+        Ok(())
+    }
+
+    /// Move the processed bytes of the write input to the output
+    fn process_write(&mut self) -> io::Result<()> {
+        if self.write_handshake {
+            let end = match self.write_in.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(pos) => pos + 4,
+                None => return Ok(()),
+            };
+            self.write_out.extend(self.write_in.drain(..end));
+            self.write_handshake = false;
+        }
+        let codec = match &mut self.codec {
+            Some(codec) => codec,
+            None => {
+                self.write_out.append(&mut self.write_in);
+                return Ok(());
+            }
+        };
+        let mut pos = 0;
+        while let Some(header) = Header::parse(&self.write_in[pos..]) {
+            let end = header.end(pos)?;
+            if self.write_in.len() < end {
+                break;
+            }
+            let frame = &self.write_in[pos..end];
+            if header.is_control() {
+                self.write_out.extend_from_slice(frame);
+            } else if header.fin && header.opcode != 0 && !self.write_fragmented {
+                let data = unmask(&frame[header.len..], header.mask);
+                let compressed = codec.compress(&data)?;
+                self.traffic
+                    .add_compressed_sent(compressed.len(), data.len());
+                let header = Header {
+                    rsv1: true,
+                    ..header
+                };
+                header.write(&mut self.write_out, &compressed);
+            } else {
+                // send the fragmented message uncompressed
+                self.write_fragmented = !header.fin;
+                self.traffic
+                    .add_compressed_sent(header.payload, header.payload);
+                self.write_out.extend_from_slice(frame);
+            }
+            pos = end;
+        }
+        self.write_in.drain(..pos);
+        Ok(())
+    }
+
+    /// Whether all bytes pass through
+    fn is_plain(&self) -> bool {
+        !self.read_handshake && !self.write_handshake && self.codec.is_none()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Write the processed bytes to the inner stream
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_out.len() {
+            let len = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_out[self.write_pos..])
+            )?;
+            if len == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += len;
+        }
+        self.write_out.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.read_pos < this.read_out.len() {
+                let len = buf.remaining().min(this.read_out.len() - this.read_pos);
+                buf.put_slice(&this.read_out[this.read_pos..this.read_pos + len]);
+                this.read_pos += len;
+                if this.read_pos == this.read_out.len() {
+                    this.read_out.clear();
+                    this.read_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.is_plain() && this.read_in.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            let mut data = [0; READ_SIZE];
+            let mut data = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut data))?;
+            if data.filled().is_empty() {
+                // pass the incomplete frame to the websocket to report the error
+                this.read_out.append(&mut this.read_in);
+                if this.read_out.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            this.read_in.extend_from_slice(data.filled());
+            this.process_read()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        if this.is_plain() && this.write_in.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        this.write_in.extend_from_slice(buf);
+        this.process_write()?;
+        // the rest is written in the next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: relay.test\r\n\r\n";
+
+    fn frame(fin: bool, rsv1: bool, opcode: u8, mask: Option<[u8; 4]>, payload: &[u8]) -> Vec<u8> {
+        let header = Header {
+            fin,
+            rsv1,
+            opcode,
+            mask,
+            len: 0,
+            payload: 0,
+        };
+        let mut out = vec![];
+        header.write(&mut out, payload);
+        out
+    }
+
+    fn response(extension: Option<&str>) -> Vec<u8> {
+        let mut res = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n".to_vec();
+        if let Some(extension) = extension {
+            res.extend_from_slice(
+                format!("Sec-WebSocket-Extensions: {}\r\n", extension).as_bytes(),
+            );
+        }
+        res.extend_from_slice(b"\r\n");
+        res
+    }
+
+    async fn expect<S: AsyncRead + Unpin>(stream: &mut S, bytes: &[u8]) {
+        let mut buf = vec![0; bytes.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, bytes);
+    }
+
+    /// Read one frame written by the client, return the header and the unmasked payload
+    async fn read_frame(stream: &mut DuplexStream) -> (Header, Vec<u8>) {
+        let mut buf = vec![];
+        loop {
+            if let Some(header) = Header::parse(&buf) {
+                let mut payload = vec![0; header.payload];
+                stream.read_exact(&mut payload).await.unwrap();
+                return (header, unmask(&payload, header.mask));
+            }
+            buf.push(stream.read_u8().await.unwrap());
+        }
+    }
+
+    #[test]
+    fn offer() {
+        assert_eq!(
+            Deflate::default().offer(),
+            "permessage-deflate; client_max_window_bits"
+        );
+        let deflate = Deflate {
+            window_bits: Some(10),
+            no_context_takeover: true,
+        };
+        assert_eq!(
+            deflate.offer(),
+            "permessage-deflate; client_max_window_bits=10; server_max_window_bits=10; \
+             client_no_context_takeover; server_no_context_takeover"
+        );
+    }
+
+    #[test]
+    fn response_params() {
+        assert_eq!(parse_response(&response(None)).unwrap(), None);
+        let header =
+            b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n";
+        assert_eq!(parse_response(header).unwrap(), None);
+        assert_eq!(
+            parse_response(&response(Some("permessage-deflate"))).unwrap(),
+            Some(Params::default())
+        );
+        assert_eq!(
+            parse_response(&response(Some(
+                "x-custom, permessage-deflate; client_max_window_bits=\"10\"; server_no_context_takeover"
+            )))
+            .unwrap(),
+            Some(Params {
+                client_max_window_bits: Some(10),
+                server_no_context_takeover: true,
+                ..Default::default()
+            })
+        );
+        for extension in [
+            "permessage-deflate; client_max_window_bits=8",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; unknown",
+        ] {
+            assert!(parse_response(&response(Some(extension))).is_err());
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let data = br#"["EVENT",{"content":"hello hello hello hello hello"}]"#;
+        // offered and accepted no context takeover, the server may omit it in the response
+        for (offered, accepted) in [(false, false), (true, true), (true, false)] {
+            let offer = Deflate {
+                no_context_takeover: offered,
+                ..Default::default()
+            };
+            let params = Params {
+                client_no_context_takeover: accepted,
+                server_no_context_takeover: accepted,
+                ..Default::default()
+            };
+            let mut client = Codec::new(&offer, params);
+            let mut server = Codec::new(&offer, params);
+            let first = client.compress(data).unwrap();
+            let second = client.compress(data).unwrap();
+            assert!(first.len() < data.len());
+            assert!(!first.ends_with(&DEFLATE_TAIL));
+            // the second message refers to the first one in the shared context
+            assert_eq!(first == second, offered);
+            assert_eq!(server.decompress(&first).unwrap(), data);
+            assert_eq!(server.decompress(&second).unwrap(), data);
+        }
+        let mut codec = Codec::new(&Deflate::default(), Params::default());
+        assert!(codec.decompress(b"\xff\xff\xff\xff").is_err());
+        // decompression bomb
+        let bomb = Codec::new(&Deflate::default(), Params::default())
+            .compress(&vec![0; MAX_FRAME_SIZE + 1])
+            .unwrap();
+        let mut codec = Codec::new(&Deflate::default(), Params::default());
+        assert_eq!(
+            codec.decompress(&bomb).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn stream() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let data = br#"["OK","id",true,"duplicate: hello hello hello hello"]"#;
+            let mut server_codec = Codec::new(&Deflate::default(), Params::default());
+            let compressed = server_codec.compress(data).unwrap();
+            let (client, mut server) = tokio::io::duplex(4096);
+            let traffic = Arc::new(Traffic::default());
+            let mut client = DeflateStream::new(client, Some(Deflate::default()), traffic.clone());
+
+            client.write_all(REQUEST).await.unwrap();
+            expect(&mut server, REQUEST).await;
+            let mut res = response(Some("permessage-deflate"));
+            res.extend(frame(true, true, 1, None, &compressed));
+            // a fragmented compressed message with a ping between the fragments
+            res.extend(frame(false, true, 1, None, &compressed[..3]));
+            res.extend(frame(true, false, 9, None, b"x"));
+            res.extend(frame(true, false, 0, None, &compressed[3..]));
+            server.write_all(&res).await.unwrap();
+
+            expect(&mut client, &response(Some("permessage-deflate"))).await;
+            assert!(client.is_negotiated());
+            expect(&mut client, &frame(true, false, 1, None, data)).await;
+            expect(&mut client, &frame(true, false, 9, None, b"x")).await;
+            expect(&mut client, &frame(true, false, 1, None, data)).await;
+
+            let mask = Some([1, 2, 3, 4]);
+            client
+                .write_all(&frame(true, false, 1, mask, data))
+                .await
+                .unwrap();
+            client
+                .write_all(&frame(true, false, 10, mask, b"x"))
+                .await
+                .unwrap();
+            client.flush().await.unwrap();
+            let (header, sent) = read_frame(&mut server).await;
+            assert!(header.rsv1 && header.fin && header.mask == mask);
+            assert_eq!(server_codec.decompress(&sent).unwrap(), data);
+            let (header, payload) = read_frame(&mut server).await;
+            assert!(!header.rsv1 && header.opcode == 10);
+            assert_eq!(payload, b"x");
+
+            let stats = traffic.compression();
+            assert_eq!(stats.received, data.len() * 2);
+            assert_eq!(stats.compressed_received, compressed.len() * 2);
+            assert_eq!(stats.sent, data.len());
+            assert_eq!(stats.compressed_sent, sent.len());
+        });
+    }
+
+    #[test]
+    fn stream_too_large() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let too_large = (MAX_FRAME_SIZE as u64 + 1).to_be_bytes();
+        for len in [u64::MAX.to_be_bytes(), too_large] {
+            let res = rt.block_on(async {
+                let (client, mut server) = tokio::io::duplex(4096);
+                let traffic = Arc::new(Traffic::default());
+                let mut client = DeflateStream::new(client, Some(Deflate::default()), traffic);
+                client.write_all(REQUEST).await.unwrap();
+                expect(&mut server, REQUEST).await;
+                let res = response(Some("permessage-deflate"));
+                server.write_all(&res).await.unwrap();
+                expect(&mut client, &res).await;
+                // only the header of the claimed payload is sent
+                server.write_all(&[0xc1, 127]).await.unwrap();
+                server.write_all(&len).await.unwrap();
+                let mut buf = [0; 16];
+                client.read(&mut buf).await
+            });
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn stream_not_negotiated() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (client, mut server) = tokio::io::duplex(4096);
+            let traffic = Arc::new(Traffic::default());
+            let mut client = DeflateStream::new(client, Some(Deflate::default()), traffic.clone());
+            client.write_all(REQUEST).await.unwrap();
+            expect(&mut server, REQUEST).await;
+            let mut res = response(None);
+            // passed through for the websocket to report the protocol error
+            res.extend(frame(true, true, 1, None, b"abc"));
+            server.write_all(&res).await.unwrap();
+            expect(&mut client, &res).await;
+            assert!(!client.is_negotiated());
+
+            let sent = frame(true, false, 1, Some([1, 2, 3, 4]), b"abc");
+            client.write_all(&sent).await.unwrap();
+            expect(&mut server, &sent).await;
+            let stats = traffic.compression();
+            assert_eq!((stats.sent, stats.received), (0, 0));
+        });
+    }
+}
@@ -1,14 +1,15 @@
+use crate::stream::WsStream;
 use crate::util::{gen_string, parse_interface};
-use crate::{add1, bench_message, BenchOpts, Error, MessageStats};
+use crate::{
+    bench_message, close, BenchOpts, BenchResult, ConnectionOpts, Error, MessageStats, Shutdown,
+};
 use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
 use parking_lot::Mutex;
-use tokio_tungstenite::MaybeTlsStream;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
 use tokio::{time, time::Duration};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
 use url::Url;
 
 /// Echo benchmark options
@@ -34,10 +35,21 @@ pub struct EchoOpts {
     #[arg(short = 't', long, default_value = "0", value_name = "NUM")]
     pub threads: usize,
 
+    /// Stop the benchmark after second, ignore when set to 0
+    #[arg(short = 'd', long, default_value = "0", value_name = "NUM")]
+    pub duration: u64,
+
+    /// Exclude the first seconds from measurements, ignore when set to 0
+    #[arg(short = 'w', long, default_value = "0", value_name = "NUM")]
+    pub warmup: u64,
+
     /// Network interface address list
     #[arg(short = 'i', long, value_name = "IP", value_parser = parse_interface)]
     pub interface: Option<Vec<SocketAddr>>,
 
+    #[command(flatten)]
+    pub connection: ConnectionOpts,
+
     /// Display stats information as json, time format as milli seconds
     #[arg(long)]
     pub json: bool,
@@ -48,7 +60,7 @@ pub struct EchoOpts {
 }
 
 /// Start bench
-pub async fn start(opts: EchoOpts) {
+pub async fn start(opts: EchoOpts) -> Result<BenchResult, Error> {
     let bench_opts = BenchOpts {
         url: opts.url,
         count: opts.count,
@@ -56,6 +68,9 @@ pub async fn start(opts: EchoOpts) {
         keepalive: opts.keepalive,
         threads: opts.threads,
         interface: opts.interface,
+        duration: opts.duration,
+        warmup: opts.warmup,
+        connection: opts.connection,
     };
 
     let stats = Arc::new(Mutex::new(MessageStats {
@@ -66,25 +81,38 @@ pub async fn start(opts: EchoOpts) {
     let c_stats = stats.clone();
     let message = gen_string(opts.size);
 
-    bench_message(bench_opts, stats, opts.json, |stream| {
-        loop_message(stream, c_stats, message)
+    bench_message(bench_opts, stats, opts.json, |stream, shutdown| {
+        loop_message(stream, c_stats, message, shutdown)
     })
-    .await;
+    .await
 }
 
 /// Loop send message
 async fn loop_message(
-    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    stream: WsStream,
     stats: Arc<Mutex<MessageStats>>,
     message: String,
+    mut shutdown: Shutdown,
 ) -> Result<(), Error> {
     let (mut write, mut read) = stream.split();
     time::sleep(Duration::from_secs(1)).await;
     let mut start = time::Instant::now();
-    add1!(stats, total);
+    let mut closing = false;
+    {
+        let mut r = stats.lock();
+        r.total += 1;
+        r.add_sent(message.len());
+    }
     write.send(Message::Text(message.clone())).await?;
     loop {
-        let msg = read.next().await;
+        let msg = tokio::select! {
+            msg = read.next() => msg,
+            _ = shutdown.wait(), if !closing => {
+                // stop after the in-flight message
+                closing = true;
+                continue;
+            }
+        };
         let message = message.clone();
         match msg {
             Some(msg) => {
@@ -92,18 +120,25 @@ async fn loop_message(
                 if msg.is_text() || msg.is_binary() {
                     {
                         let mut r = stats.lock();
-                        r.size += msg.len() + message.len();
+                        r.add_received(msg.len());
+                    }
+                    {
+                        let mut r = stats.lock();
+                        r.add_complete(start);
+                    }
+                    if closing {
+                        return close(&mut write, &mut read).await;
                     }
                     {
                         let mut r = stats.lock();
-                        r.success_time = r.success_time.add(start.elapsed());
+                        r.total += 1;
+                        r.add_sent(message.len());
                     }
-                    add1!(stats, complete, total);
                     // let event = "test".to_string();
                     start = time::Instant::now();
                     write.send(Message::Text(message)).await?;
-                } else if msg.is_close() {
-                    break;
+                } else if let Message::Close(frame) = msg {
+                    return Err(Error::Closed(frame));
                 }
             }
             None => break,
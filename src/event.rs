@@ -1,14 +1,20 @@
-use crate::util::{gen_note_event, parse_interface};
-use crate::{add1, bench_message, BenchOpts, Error, MessageStats};
+use crate::stream::WsStream;
+use crate::util::{gen_note, parse_interface};
+use crate::{
+    bench_message, close, BenchOpts, BenchResult, ConnectionOpts, Error, MessageStats, Shutdown,
+};
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, StreamExt};
+use nostr::{ClientMessage, EventId, RelayMessage};
 use parking_lot::Mutex;
-use tokio_tungstenite::MaybeTlsStream;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::{time, time::Duration};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::{
+    time,
+    time::{Duration, MissedTickBehavior},
+};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 use url::Url;
 const BENCH_CONTENT: &str = "This is a message from nostr-bench client";
 
@@ -35,17 +41,42 @@ pub struct EventOpts {
     #[arg(short = 't', long, default_value = "0", value_name = "NUM")]
     pub threads: usize,
 
+    /// Stop the benchmark after second, ignore when set to 0
+    #[arg(short = 'd', long, default_value = "0", value_name = "NUM")]
+    pub duration: u64,
+
+    /// Exclude the first seconds from measurements, ignore when set to 0
+    #[arg(short = 'w', long, default_value = "0", value_name = "NUM")]
+    pub warmup: u64,
+
     /// Network interface address list
     #[arg(short = 'i', long, value_name = "IP", value_parser = parse_interface)]
     pub interface: Option<Vec<SocketAddr>>,
 
+    #[command(flatten)]
+    pub connection: ConnectionOpts,
+
     /// Display stats information as json, time format as milli seconds
     #[arg(long)]
     pub json: bool,
+
+    /// Open loop mode, send total events every second spread across clients regardless of responses, ignore when set to 0
+    ///
+    /// Every client sends tps/count events per second from its start, so the total rate is reached only after all clients are connected
+    #[arg(long, default_value = "0", value_name = "NUM")]
+    pub tps: usize,
+
+    /// Max count of in-flight events every client in closed loop mode
+    #[arg(long, default_value = "1", value_name = "NUM", value_parser = clap::value_parser!(u64).range(1..))]
+    pub pipeline: u64,
+
+    /// Count an event as error when no OK message is received in seconds
+    #[arg(long, default_value = "10", value_name = "NUM", value_parser = clap::value_parser!(u64).range(1..))]
+    pub ok_timeout: u64,
 }
 
 /// Start bench
-pub async fn start(opts: EventOpts) {
+pub async fn start(opts: EventOpts) -> Result<BenchResult, Error> {
     let bench_opts = BenchOpts {
         url: opts.url,
         count: opts.count,
@@ -53,6 +84,9 @@ pub async fn start(opts: EventOpts) {
         keepalive: opts.keepalive,
         threads: opts.threads,
         interface: opts.interface,
+        duration: opts.duration,
+        warmup: opts.warmup,
+        connection: opts.connection,
     };
 
     let stats = Arc::new(Mutex::new(MessageStats {
@@ -61,51 +95,173 @@ pub async fn start(opts: EventOpts) {
     }));
 
     let c_stats = stats.clone();
+    let pipeline = opts.pipeline as usize;
+    let timeout = Duration::from_secs(opts.ok_timeout);
+    // interval of each client to reach the total tps
+    let interval = if opts.tps > 0 {
+        Some(Duration::from_secs_f64(opts.count as f64 / opts.tps as f64))
+    } else {
+        None
+    };
 
-    bench_message(bench_opts, stats, opts.json, |stream| {
-        loop_event(stream, c_stats)
-    })
-    .await;
+    bench_message(
+        bench_opts,
+        stats,
+        opts.json,
+        move |stream, shutdown| async move {
+            match interval {
+                Some(interval) => {
+                    loop_event_open(stream, c_stats, interval, timeout, shutdown).await
+                }
+                None => loop_event(stream, c_stats, pipeline, timeout, shutdown).await,
+            }
+        },
+    )
+    .await
 }
 
-/// Loop send event
+/// Generate event and send, the latency is measured from the intended send time or now
+async fn send_event<W>(
+    write: &mut W,
+    pending: &mut HashMap<EventId, time::Instant>,
+    intended: Option<time::Instant>,
+    stats: &Mutex<MessageStats>,
+) -> Result<(), Error>
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+{
+    let event = gen_note(BENCH_CONTENT);
+    pending.insert(event.id, intended.unwrap_or_else(time::Instant::now));
+    let event = ClientMessage::new_event(event).as_json();
+    {
+        let mut r = stats.lock();
+        r.add_sent(event.len());
+        r.total += 1;
+    }
+    write.send(Message::Text(event)).await?;
+    Ok(())
+}
+
+/// Match OK message with the pending event, return true when an event completed
+fn receive(
+    msg: &str,
+    pending: &mut HashMap<EventId, time::Instant>,
+    stats: &Mutex<MessageStats>,
+) -> bool {
+    let mut r = stats.lock();
+    r.add_received(msg.len());
+    match RelayMessage::from_json(msg) {
+        Ok(RelayMessage::Ok {
+            event_id,
+            status,
+            message,
+        }) => match pending.remove(&event_id) {
+            Some(start) => {
+                // drop the event sent in the warm-up
+                if !r.measured(start) {
+                    return true;
+                }
+                if status {
+                    r.add_success_time(start.elapsed());
+                } else {
+                    // println!("message error {:?}", msg);
+                    r.error += 1;
+                    r.add_reason("ok", &message);
+                }
+                r.ok.add(status, &message);
+                r.complete += 1;
+                r.event += 1;
+                return true;
+            }
+            None => r.unexpected += 1,
+        },
+        Ok(RelayMessage::Notice { message }) => {
+            r.unexpected += 1;
+            r.add_reason("notice", &message);
+        }
+        _ => r.unexpected += 1,
+    }
+    false
+}
+
+/// Remove the events waiting for OK longer than the timeout, return the count removed
+fn expire(
+    pending: &mut HashMap<EventId, time::Instant>,
+    timeout: Duration,
+    stats: &Mutex<MessageStats>,
+) -> usize {
+    let now = time::Instant::now();
+    let len = pending.len();
+    let mut r = stats.lock();
+    pending.retain(|_, start| {
+        if now.saturating_duration_since(*start) < timeout {
+            return true;
+        }
+        r.add_timeout(*start);
+        false
+    });
+    len - pending.len()
+}
+
+/// Ticker to check the expired events
+fn expire_ticker(timeout: Duration) -> time::Interval {
+    let period = timeout.min(Duration::from_secs(1));
+    let mut ticker = time::interval_at(time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
+
+/// Loop send event, keep pipeline count of events in flight
 pub async fn loop_event(
-    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    stream: WsStream,
     stats: Arc<Mutex<MessageStats>>,
+    pipeline: usize,
+    timeout: Duration,
+    mut shutdown: Shutdown,
 ) -> Result<(), Error> {
     let (mut write, mut read) = stream.split();
-    let event = gen_note_event(BENCH_CONTENT);
     time::sleep(Duration::from_secs(1)).await;
-    let mut start = time::Instant::now();
-    add1!(stats, total);
-    write.send(Message::Text(event)).await?;
+    let mut pending = HashMap::new();
+    let mut closing = false;
+    let mut expire_ticker = expire_ticker(timeout);
+    for _ in 0..pipeline {
+        send_event(&mut write, &mut pending, None, &stats).await?;
+    }
     loop {
-        let msg = read.next().await;
+        let msg = tokio::select! {
+            msg = read.next() => msg,
+            _ = expire_ticker.tick() => {
+                // replace the expired events to keep the pipeline full
+                let expired = expire(&mut pending, timeout, &stats);
+                if !closing {
+                    for _ in 0..expired {
+                        send_event(&mut write, &mut pending, None, &stats).await?;
+                    }
+                } else if pending.is_empty() {
+                    return close(&mut write, &mut read).await;
+                }
+                continue;
+            }
+            _ = shutdown.wait(), if !closing => {
+                // stop after the in-flight events
+                closing = true;
+                if pending.is_empty() {
+                    return close(&mut write, &mut read).await;
+                }
+                continue;
+            }
+        };
         match msg {
             Some(msg) => {
                 let msg = msg?;
-                if msg.is_text() {
-                    let event = gen_note_event(BENCH_CONTENT);
-                    let msg = msg.to_string();
-                    {
-                        let mut r = stats.lock();
-                        r.size += msg.len() + event.len();
-                    }
-                    if msg.contains("OK") && msg.contains("true") {
-                        {
-                            let mut r = stats.lock();
-                            r.success_time = r.success_time.add(start.elapsed());
-                        }
-                    } else {
-                        // println!("message error {:?}", msg);
-                        add1!(stats, error);
+                if msg.is_text() && receive(msg.to_text()?, &mut pending, &stats) {
+                    if !closing {
+                        send_event(&mut write, &mut pending, None, &stats).await?;
+                    } else if pending.is_empty() {
+                        return close(&mut write, &mut read).await;
                     }
-                    add1!(stats, complete, total, event);
-                    // let event = "test".to_string();
-                    start = time::Instant::now();
-                    write.send(Message::Text(event)).await?;
-                } else if msg.is_close() {
-                    break;
+                } else if let Message::Close(frame) = msg {
+                    return Err(Error::Closed(frame));
                 }
             }
             None => break,
@@ -113,3 +269,83 @@ pub async fn loop_event(
     }
     Ok(())
 }
+
+/// Send event on schedule regardless of responses, latency is measured from the intended send time
+pub async fn loop_event_open(
+    stream: WsStream,
+    stats: Arc<Mutex<MessageStats>>,
+    interval: Duration,
+    timeout: Duration,
+    mut shutdown: Shutdown,
+) -> Result<(), Error> {
+    let (mut write, mut read) = stream.split();
+    time::sleep(Duration::from_secs(1)).await;
+    let mut expire_ticker = expire_ticker(timeout);
+    let mut ticker = time::interval(interval);
+    // catch up missed sends to keep the arrival rate
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let mut pending = HashMap::new();
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            intended = ticker.tick(), if !closing => {
+                send_event(&mut write, &mut pending, Some(intended), &stats).await?;
+            }
+            _ = expire_ticker.tick() => {
+                expire(&mut pending, timeout, &stats);
+                if closing && pending.is_empty() {
+                    return close(&mut write, &mut read).await;
+                }
+            }
+            msg = read.next() => {
+                let msg = match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                };
+                if msg.is_text() {
+                    if receive(msg.to_text()?, &mut pending, &stats) && closing && pending.is_empty() {
+                        return close(&mut write, &mut read).await;
+                    }
+                } else if let Message::Close(frame) = msg {
+                    return Err(Error::Closed(frame));
+                }
+            }
+            _ = shutdown.wait(), if !closing => {
+                // stop sending, wait for the in-flight events
+                closing = true;
+                if pending.is_empty() {
+                    return close(&mut write, &mut read).await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expire;
+    use crate::MessageStats;
+    use nostr::EventId;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn expire_pending() {
+        let now = Instant::now();
+        let stats = Mutex::new(MessageStats::default());
+        let mut pending = HashMap::new();
+        pending.insert(EventId::from_slice(&[1; 32]).unwrap(), now);
+        pending.insert(
+            EventId::from_slice(&[2; 32]).unwrap(),
+            now - Duration::from_secs(20),
+        );
+        assert_eq!(expire(&mut pending, Duration::from_secs(10), &stats), 1);
+        assert_eq!(pending.len(), 1);
+        let stats = stats.lock();
+        assert_eq!(stats.timeout, 1);
+        assert_eq!(stats.error, 1);
+        assert_eq!(stats.complete, 1);
+    }
+}
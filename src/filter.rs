@@ -0,0 +1,180 @@
+use crate::util::gen_hashtag;
+use nostr::prelude::{rand, rand::Rng};
+use nostr::{Filter, Keys, Timestamp};
+use serde_json::{Map, Value};
+use std::fs;
+
+/// Filter fields validated by nostr Filter, other single-letter tags are kept as is
+const FILTER_FIELDS: [&str; 11] = [
+    "ids", "authors", "kinds", "#e", "#p", "#t", "#r", "search", "since", "until", "limit",
+];
+
+/// REQ filters template, string values as placeholders are replaced with random values
+///
+/// - `{id}` random 32 bytes hex
+/// - `{pubkey}` random public key hex
+/// - `{hashtag}` random hashtag of the generated events
+/// - `{now}` current unix timestamp, `{now-N}` N seconds ago
+#[derive(Debug, Clone)]
+pub struct FilterTemplate(Vec<Map<String, Value>>);
+
+/// Parse filter template from json or file, accept a filter object or an array of filters
+pub fn parse_filter(s: &str) -> Result<FilterTemplate, String> {
+    let json = if s.trim_start().starts_with(['{', '[']) {
+        s.to_owned()
+    } else {
+        fs::read_to_string(s).map_err(|e| format!("{}: {}", s, e))?
+    };
+    let filters = match serde_json::from_str(&json).map_err(|e| e.to_string())? {
+        Value::Object(filter) => vec![filter],
+        Value::Array(filters) => filters
+            .into_iter()
+            .map(|filter| match filter {
+                Value::Object(filter) => Ok(filter),
+                _ => Err("filter must be an object".to_owned()),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => return Err("filter must be an object or an array of objects".to_owned()),
+    };
+    if filters.is_empty() {
+        return Err("no filter".to_owned());
+    }
+    let template = FilterTemplate(filters);
+    // check the template once, rendering never fails later
+    template.render()?;
+    Ok(template)
+}
+
+impl FilterTemplate {
+    /// Generate filters with new random values
+    pub fn render(&self) -> Result<Vec<Value>, String> {
+        self.0.iter().map(render_filter).collect()
+    }
+
+    /// Generate request
+    pub fn gen_req(&self, id: Option<String>) -> String {
+        let id = id.unwrap_or("sub".to_owned());
+        let mut req = vec![Value::from("REQ"), Value::from(id)];
+        req.extend(self.render().unwrap());
+        Value::Array(req).to_string()
+    }
+}
+
+/// Replace placeholders and validate through nostr Filter
+fn render_filter(template: &Map<String, Value>) -> Result<Value, String> {
+    let mut fields = Map::new();
+    let mut tags = Map::new();
+    for (key, value) in template {
+        let value = replace(value)?;
+        // nostr Filter keeps ids as plain strings
+        if key == "ids" && !value.as_array().is_some_and(|ids| ids.iter().all(is_id)) {
+            return Err("ids must be an array of 64 character hex strings".to_owned());
+        }
+        if FILTER_FIELDS.contains(&key.as_str()) {
+            fields.insert(key.clone(), value);
+            continue;
+        }
+        match key.strip_prefix('#') {
+            Some(tag) if tag.len() == 1 && tag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                let valid = value
+                    .as_array()
+                    .is_some_and(|values| values.iter().all(Value::is_string));
+                if !valid {
+                    return Err(format!("{} must be an array of strings", key));
+                }
+                tags.insert(key.clone(), value);
+            }
+            _ => return Err(format!("unknown filter field: {}", key)),
+        }
+    }
+    let filter: Filter =
+        serde_json::from_value(Value::Object(fields)).map_err(|e| e.to_string())?;
+    match serde_json::to_value(filter).map_err(|e| e.to_string())? {
+        Value::Object(mut filter) => {
+            filter.extend(tags);
+            Ok(Value::Object(filter))
+        }
+        _ => Err("filter must be an object".to_owned()),
+    }
+}
+
+/// Whether the value is an event id, 32 bytes hex
+fn is_id(value: &Value) -> bool {
+    value
+        .as_str()
+        .is_some_and(|id| id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Replace the placeholder values recursively
+fn replace(value: &Value) -> Result<Value, String> {
+    Ok(match value {
+        Value::Array(values) => Value::Array(values.iter().map(replace).collect::<Result<_, _>>()?),
+        Value::String(s) => match s.as_str() {
+            "{id}" => {
+                let bytes: [u8; 32] = rand::thread_rng().gen();
+                Value::from(
+                    bytes
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>(),
+                )
+            }
+            "{pubkey}" => Value::from(Keys::generate().public_key().to_string()),
+            "{hashtag}" => Value::from(gen_hashtag()),
+            "{now}" => Value::from(Timestamp::now().as_u64()),
+            _ => match s.strip_prefix("{now-").and_then(|s| s.strip_suffix('}')) {
+                Some(secs) => {
+                    let secs = secs
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid placeholder: {}", s))?;
+                    Value::from(Timestamp::now().as_u64().saturating_sub(secs))
+                }
+                None => value.clone(),
+            },
+        },
+        _ => value.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_filter;
+    use serde_json::Value;
+
+    #[test]
+    fn filter() {
+        let template = parse_filter(
+            r##"[{"authors": ["{pubkey}"], "kinds": [1, 7], "since": "{now-3600}", "#d": ["x"]},
+                {"ids": ["{id}"], "#t": ["{hashtag}"], "limit": 10}]"##,
+        )
+        .unwrap();
+        let filters = template.render().unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0]["authors"][0].as_str().unwrap().len(), 64);
+        assert_eq!(filters[0]["kinds"], serde_json::json!([1, 7]));
+        assert!(filters[0]["since"].is_u64());
+        assert_eq!(filters[0]["#d"], serde_json::json!(["x"]));
+        assert_eq!(filters[1]["ids"][0].as_str().unwrap().len(), 64);
+        assert!(filters[1]["#t"][0]
+            .as_str()
+            .unwrap()
+            .starts_with("nostr-bench-"));
+
+        let req: Value = serde_json::from_str(&template.gen_req(Some("id".to_owned()))).unwrap();
+        assert_eq!(req[0], "REQ");
+        assert_eq!(req[1], "id");
+        assert_eq!(req.as_array().unwrap().len(), 4);
+
+        assert!(parse_filter(r#"{"kinds": 1}"#).is_err());
+        assert!(parse_filter(r#"{"authors": ["abc"]}"#).is_err());
+        assert!(parse_filter(r#"{"ids": ["not-hex"]}"#).is_err());
+        assert!(parse_filter(&format!(r#"{{"ids": ["{}"]}}"#, "g".repeat(64))).is_err());
+        assert!(parse_filter(&format!(r#"{{"ids": ["{}"]}}"#, "ab".repeat(32))).is_ok());
+        assert!(parse_filter(r#"{"since": "{now-x}"}"#).is_err());
+        assert!(parse_filter(r##"{"#dd": ["x"]}"##).is_err());
+        assert!(parse_filter(r##"{"#d": "x"}"##).is_err());
+        assert!(parse_filter(r#"{"unknown": 1}"#).is_err());
+        assert!(parse_filter("[]").is_err());
+        assert!(parse_filter("/nonexistent/filter.json").is_err());
+    }
+}
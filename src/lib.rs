@@ -1,30 +1,56 @@
+use clap::Args;
+use deflate::{Deflate, DeflateStream};
 use futures_util::{
-    future::{join_all, select},
-    Future,
+    future::{join_all, pending},
+    Future, Sink, SinkExt, Stream, StreamExt,
 };
+use hdrhistogram::Histogram;
 use parking_lot::Mutex;
-use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DurationMilliSeconds};
+use proxy::{parse_proxy, Proxy};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_with::{serde_as, DurationMilliSeconds, DurationMilliSecondsWithFrac};
 use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::Arc;
-use tokio::{
-    net::{TcpSocket, TcpStream},
-    time,
-    time::Duration,
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
 };
+use stream::{CountingStream, Traffic, WsStream};
+use tls::Tls;
+use tokio::{net::TcpSocket, signal, sync::watch, time, time::Duration};
 use tokio_tungstenite::{
-    client_async_tls, tungstenite::Error as WsError, MaybeTlsStream, WebSocketStream,
+    client_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        error::ProtocolError,
+        handshake::client::Response,
+        http::{header::SEC_WEBSOCKET_EXTENSIONS, HeaderName, HeaderValue},
+        protocol::CloseFrame,
+        Error as WsError, Message,
+    },
+    MaybeTlsStream,
 };
 use url::Url;
-use util::parse_wsaddr;
+use util::{
+    parse_header, parse_resolve, parse_wsaddr, resolve_wsaddrs, Backoff, Balance, Interfaces,
+    Resolve,
+};
 
 pub mod connect;
+pub mod deflate;
 pub mod echo;
 pub mod event;
+pub mod filter;
+pub mod proxy;
 pub mod req;
 pub mod runtime;
+pub mod stream;
+pub mod tls;
 pub mod util;
 
 #[macro_export]
@@ -56,15 +82,140 @@ pub enum Error {
     /// Ws error
     #[error("ws error: {0}")]
     Ws(#[from] WsError),
+    /// Resolve relay address error
+    #[error("dns error: {0}")]
+    Dns(std::io::Error),
+    /// TLS handshake error
+    #[error("tls error: {0}")]
+    Tls(std::io::Error),
+    /// Proxy handshake error
+    #[error("proxy error: {0}")]
+    Proxy(std::io::Error),
     /// Connect timeout
     #[error("connect timeout")]
     ConnectTimeout,
+    /// Proxy handshake timeout
+    #[error("proxy timeout")]
+    ProxyTimeout,
+    /// Handshake timeout
+    #[error("handshake timeout")]
+    HandshakeTimeout,
     /// Alive timeout
     #[error("alive timeout")]
     AliveTimeout,
-    /// Lost connection
-    #[error("lost connection")]
-    Lost,
+    /// Closed by server
+    #[error("closed by server: {}", close_reason(.0))]
+    Closed(Option<CloseFrame<'static>>),
+}
+
+impl Error {
+    /// Whether the connection setup timed out, counted as timeout instead of error
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::ConnectTimeout | Error::ProxyTimeout | Error::HandshakeTimeout => true,
+            Error::IO(err) | Error::Ws(WsError::Io(err)) => err.kind() == ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+}
+
+/// Connection setup options shared by all benchmarks
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConnectionOpts {
+    /// Distribute connections across all resolved relay addresses
+    #[arg(long, value_enum, default_value_t = Balance::First, value_name = "MODE")]
+    pub balance: Balance,
+
+    /// Use custom addresses for host and port, format as host:port:addr[,addr]...
+    #[arg(long, value_name = "HOST:PORT:ADDR", value_parser = parse_resolve)]
+    pub resolve: Vec<Resolve>,
+
+    /// Add header to the websocket upgrade request, format as "Name: value"
+    #[arg(short = 'H', long, value_name = "HEADER", value_parser = parse_header)]
+    pub header: Vec<(HeaderName, HeaderValue)>,
+
+    /// Print response headers of the first connection to stderr
+    #[arg(long)]
+    pub verbose: bool,
+
+    /// Connect through proxy, format as http://host:port or socks5://host:port
+    #[arg(long, value_name = "URL", value_parser = parse_proxy)]
+    pub proxy: Option<Proxy>,
+
+    /// TCP connect and proxy handshake timeout second each, ignore when set to 0
+    #[arg(long, default_value = "10", value_name = "NUM")]
+    pub connect_timeout: u64,
+
+    /// TLS and websocket handshake timeout second, ignore when set to 0
+    #[arg(long, default_value = "60", value_name = "NUM")]
+    pub handshake_timeout: u64,
+
+    /// Reconnect lost connections with exponential backoff
+    #[arg(long)]
+    pub reconnect: bool,
+
+    /// Initial reconnect delay milli second
+    #[arg(long, default_value = "100", value_name = "MS")]
+    pub reconnect_min: u64,
+
+    /// Max reconnect delay milli second
+    #[arg(long, default_value = "10000", value_name = "MS")]
+    pub reconnect_max: u64,
+
+    /// Randomly reduce reconnect delay by up to percent
+    #[arg(long, default_value = "20", value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub reconnect_jitter: u8,
+
+    /// Print one of every NUM connect errors to stderr, ignore when set to 0
+    #[arg(long, default_value = "0", value_name = "NUM")]
+    pub error_sample: usize,
+
+    /// Add CA certificates from PEM file to the trusted roots
+    #[arg(long, value_name = "FILE")]
+    pub cacert: Option<PathBuf>,
+
+    /// Client certificate chain PEM file for mutual TLS
+    #[arg(long, value_name = "FILE", requires = "key")]
+    pub cert: Option<PathBuf>,
+
+    /// Client private key PEM file for mutual TLS
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    pub key: Option<PathBuf>,
+
+    /// Skip TLS certificate verification, for lab use only
+    #[arg(long)]
+    pub insecure: bool,
+
+    /// TLS server name, default is the url host
+    #[arg(long, value_name = "NAME")]
+    pub sni: Option<String>,
+
+    /// Offer permessage-deflate compression in the websocket handshake
+    #[arg(long)]
+    pub deflate: bool,
+
+    /// Max window bits of permessage-deflate for both sides
+    #[arg(long, value_name = "NUM", requires = "deflate", value_parser = clap::value_parser!(u8).range(9..=15))]
+    pub deflate_window_bits: Option<u8>,
+
+    /// Offer permessage-deflate without context takeover for both sides
+    #[arg(long, requires = "deflate")]
+    pub deflate_no_context_takeover: bool,
+}
+
+impl ConnectionOpts {
+    /// Url of the first hop, the proxy when set
+    pub fn dial_url<'a>(&'a self, url: &'a Url) -> &'a Url {
+        self.proxy.as_ref().map_or(url, |proxy| &proxy.url)
+    }
+
+    /// permessage-deflate offer, none when disabled
+    pub fn deflate(&self) -> Option<Deflate> {
+        self.deflate.then_some(Deflate {
+            window_bits: self.deflate_window_bits,
+            no_context_takeover: self.deflate_no_context_takeover,
+        })
+    }
 }
 
 /// Connection options
@@ -87,45 +238,350 @@ pub struct BenchOpts {
 
     /// Network interface address list
     pub interface: Option<Vec<SocketAddr>>,
+
+    /// Stop the benchmark after second, ignore when set to 0
+    pub duration: u64,
+
+    /// Exclude the first seconds from measurements, ignore when set to 0
+    pub warmup: u64,
+
+    /// Connection setup options
+    pub connection: ConnectionOpts,
 }
 
-/// Time stats
+/// Time to wait for in-flight requests when shutting down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Shutdown signal shared by all connections
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Whether shutdown has been triggered
+    pub fn is_shutdown(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until shutdown is triggered
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Highest latency tracked by the histogram, as micro seconds
+const HISTOGRAM_MAX: u64 = 3_600_000_000;
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, HISTOGRAM_MAX, 3).unwrap()
+}
+
+fn serialize_percentiles<S: Serializer>(
+    histogram: &Histogram<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    Percentiles::from(histogram).serialize(serializer)
+}
+
+/// Latency percentiles
 #[serde_as]
 #[derive(Default, Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct Percentiles {
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+    pub p50: Duration,
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+    pub p90: Duration,
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+    pub p99: Duration,
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+    pub p999: Duration,
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
+    pub p9999: Duration,
+}
+
+impl From<&Histogram<u64>> for Percentiles {
+    fn from(histogram: &Histogram<u64>) -> Self {
+        let at = |q| Duration::from_micros(histogram.value_at_quantile(q));
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            p999: at(0.999),
+            p9999: at(0.9999),
+        }
+    }
+}
+
+/// Time stats
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TimeStats {
     pub count: usize,
-    /// total time, milli seconds in json format
-    #[serde_as(as = "DurationMilliSeconds")]
+    /// total time, fractional milli seconds in json format
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
     pub total: Duration,
-    #[serde_as(as = "DurationMilliSeconds")]
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
     pub avg: Duration,
-    #[serde_as(as = "DurationMilliSeconds")]
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
     pub min: Duration,
-    #[serde_as(as = "DurationMilliSeconds")]
+    #[serde_as(as = "DurationMilliSecondsWithFrac<f64>")]
     pub max: Duration,
+    /// latency distribution as micro seconds, serialized as percentiles
+    #[serde(
+        rename = "percentiles",
+        serialize_with = "serialize_percentiles",
+        skip_deserializing,
+        default = "new_histogram"
+    )]
+    pub histogram: Histogram<u64>,
+}
+
+impl Default for TimeStats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            total: Duration::ZERO,
+            avg: Duration::ZERO,
+            min: Duration::ZERO,
+            max: Duration::ZERO,
+            histogram: new_histogram(),
+        }
+    }
 }
 
 impl TimeStats {
-    pub fn add(self, time: Duration) -> Self {
-        let total = self.total + time;
-        let min = if self.min.is_zero() {
+    pub fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.total += time;
+        self.avg = self.total / self.count.try_into().unwrap();
+        self.min = if self.min.is_zero() {
             time
         } else {
             cmp::min(self.min, time)
         };
-        Self {
-            count: self.count + 1,
-            total,
-            avg: total / (self.count + 1).try_into().unwrap(),
-            min,
-            max: cmp::max(time, self.max),
+        self.max = cmp::max(time, self.max);
+        self.histogram
+            .saturating_record(time.as_micros().try_into().unwrap_or(HISTOGRAM_MAX));
+    }
+
+    /// Merge stats from another one
+    pub fn merge(&mut self, other: &TimeStats) {
+        if other.count == 0 {
+            return;
+        }
+        self.min = if self.min.is_zero() {
+            other.min
+        } else {
+            cmp::min(self.min, other.min)
+        };
+        self.max = cmp::max(self.max, other.max);
+        self.count += other.count;
+        self.total += other.total;
+        self.avg = self.total / self.count.try_into().unwrap();
+        self.histogram.add(&other.histogram).unwrap();
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        Percentiles::from(&self.histogram)
+    }
+}
+
+/// Format time as milli seconds with 0.01ms precision
+fn millis(time: Duration) -> String {
+    format!("{:.2}ms", time.as_secs_f64() * 1000.0)
+}
+
+impl fmt::Display for TimeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = self.percentiles();
+        write!(
+            f,
+            "avg: {} max: {} min: {} p50: {} p90: {} p99: {} p99.9: {} p99.99: {}",
+            millis(self.avg),
+            millis(self.max),
+            millis(self.min),
+            millis(p.p50),
+            millis(p.p90),
+            millis(p.p99),
+            millis(p.p999),
+            millis(p.p9999),
+        )
+    }
+}
+
+/// Time spent in each phase of one connection setup
+#[derive(Default, Debug, Copy, Clone)]
+pub struct PhaseTimes {
+    /// resolve relay address, none when the address is given
+    pub dns: Option<Duration>,
+    /// tcp connect
+    pub tcp: Duration,
+    /// proxy handshake, none without proxy
+    pub proxy: Option<Duration>,
+    /// tls handshake, none for ws url
+    pub tls: Option<Duration>,
+    /// websocket http upgrade
+    pub upgrade: Duration,
+}
+
+/// Connection setup phase stats
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct PhaseStats {
+    pub dns: TimeStats,
+    pub tcp: TimeStats,
+    pub proxy: TimeStats,
+    pub tls: TimeStats,
+    pub upgrade: TimeStats,
+}
+
+impl PhaseStats {
+    pub fn add(&mut self, times: &PhaseTimes) {
+        if let Some(dns) = times.dns {
+            self.dns.add(dns);
+        }
+        self.tcp.add(times.tcp);
+        if let Some(proxy) = times.proxy {
+            self.proxy.add(proxy);
+        }
+        if let Some(tls) = times.tls {
+            self.tls.add(tls);
+        }
+        self.upgrade.add(times.upgrade);
+    }
+
+    /// Name and time stats of the phases that have been measured
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &TimeStats)> {
+        [
+            ("dns", &self.dns),
+            ("tcp", &self.tcp),
+            ("proxy", &self.proxy),
+            ("tls", &self.tls),
+            ("upgrade", &self.upgrade),
+        ]
+        .into_iter()
+        .filter(|(_, time)| time.count > 0)
+    }
+}
+
+/// Display average time of each phase
+impl fmt::Display for PhaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dns: {} tcp: {} proxy: {} tls: {} upgrade: {}",
+            millis(self.dns.avg),
+            millis(self.tcp.avg),
+            millis(self.proxy.avg),
+            millis(self.tls.avg),
+            millis(self.upgrade.avg),
+        )
+    }
+}
+
+/// Connect error stats by kind
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct ConnectErrors {
+    /// num of resolve relay address failure
+    pub dns: usize,
+    /// num of connection refused
+    pub refused: usize,
+    /// num of local address in use, usually local port exhaustion
+    pub addr_in_use: usize,
+    /// num of address not available, such as no interface address of the target family
+    pub addr_not_available: usize,
+    /// num of tcp connect timeout
+    pub connect_timeout: usize,
+    /// num of proxy handshake failure
+    pub proxy: usize,
+    /// num of proxy handshake timeout
+    pub proxy_timeout: usize,
+    /// num of tls handshake failure
+    pub tls: usize,
+    /// num of http response without upgrade by status code
+    pub status: BTreeMap<u16, usize>,
+    /// num of tls and websocket handshake timeout
+    pub handshake_timeout: usize,
+    /// num of other error
+    pub other: usize,
+}
+
+impl ConnectErrors {
+    pub fn add(&mut self, err: &Error) {
+        match err {
+            Error::Dns(_) => self.dns += 1,
+            Error::Tls(_) => self.tls += 1,
+            Error::Proxy(_) => self.proxy += 1,
+            Error::ConnectTimeout => self.connect_timeout += 1,
+            Error::ProxyTimeout => self.proxy_timeout += 1,
+            Error::HandshakeTimeout => self.handshake_timeout += 1,
+            Error::IO(err) | Error::Ws(WsError::Io(err)) => match err.kind() {
+                ErrorKind::ConnectionRefused => self.refused += 1,
+                ErrorKind::AddrInUse => self.addr_in_use += 1,
+                ErrorKind::AddrNotAvailable => self.addr_not_available += 1,
+                ErrorKind::TimedOut => self.connect_timeout += 1,
+                _ => self.other += 1,
+            },
+            Error::Ws(WsError::Http(res)) => {
+                *self.status.entry(res.status().as_u16()).or_default() += 1;
+            }
+            _ => self.other += 1,
         }
     }
+
+    pub fn total(&self) -> usize {
+        self.dns
+            + self.refused
+            + self.addr_in_use
+            + self.addr_not_available
+            + self.connect_timeout
+            + self.proxy
+            + self.proxy_timeout
+            + self.tls
+            + self.status.values().sum::<usize>()
+            + self.handshake_timeout
+            + self.other
+    }
+}
+
+/// Display the error kinds that occurred
+impl fmt::Display for ConnectErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kinds = vec![];
+        for (name, num) in [
+            ("dns", self.dns),
+            ("refused", self.refused),
+            ("addr in use", self.addr_in_use),
+            ("addr not available", self.addr_not_available),
+            ("connect timeout", self.connect_timeout),
+            ("proxy", self.proxy),
+            ("proxy timeout", self.proxy_timeout),
+            ("tls", self.tls),
+        ] {
+            if num > 0 {
+                kinds.push(format!("{}: {}", name, num));
+            }
+        }
+        for (code, num) in &self.status {
+            kinds.push(format!("status {}: {}", code, num));
+        }
+        for (name, num) in [
+            ("handshake timeout", self.handshake_timeout),
+            ("other", self.other),
+        ] {
+            if num > 0 {
+                kinds.push(format!("{}: {}", name, num));
+            }
+        }
+        write!(f, "{}", kinds.join(", "))
+    }
 }
 
 /// Connect stats
 #[serde_as]
-#[derive(Default, Debug, Copy, Clone, Deserialize, Serialize)]
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct ConnectStats {
     /// total
     pub total: usize,
@@ -135,21 +591,314 @@ pub struct ConnectStats {
     pub connect: usize,
     /// num of connecting
     pub alive: usize,
-    /// num of connect error
+    /// num of connect error, exclude timeout
     pub error: usize,
-    /// num of lost connection by some error
+    /// connect error by kind, include timeout
+    pub errors: ConnectErrors,
+    /// num of lost connection by other error
     pub lost: usize,
-    /// num of closed when alive timeout
+    /// num of closed when alive timeout or shutdown
     pub close: usize,
+    /// num of handler ended normally
+    pub normal: usize,
+    /// num of closed by server
+    pub server_close: usize,
+    /// num of closed by server with code and reason, the reason is truncated
+    pub close_reasons: BTreeMap<String, usize>,
+    /// num of connection reset or closed without close frame
+    pub reset: usize,
+    /// num of websocket protocol error
+    pub protocol_error: usize,
+    /// num of reconnected after lost
+    pub reconnect: usize,
+    /// num of failed reconnect attempts, not counted in connect error
+    pub reconnect_error: usize,
+    /// time from lost to reconnected
+    pub reconnect_time: TimeStats,
     /// time duration when connected
     #[serde_as(as = "DurationMilliSeconds")]
     pub time: Duration,
     /// success connect times result
     pub success_time: TimeStats,
+    /// success connect times of each phase
+    pub phase: PhaseStats,
+    /// success connect times of each phase since last print
+    #[serde(skip)]
+    pub window_phase: PhaseStats,
+    /// bytes sent at tcp level, include proxy, tls and websocket framing
+    pub sent: usize,
+    /// bytes received at tcp level, include proxy, tls and websocket framing
+    pub received: usize,
+    /// num of connections negotiated permessage-deflate
+    pub deflate: usize,
+    /// message payload bytes of the compressed connections
+    pub compression: CompressionStats,
 }
 
-/// Message stats
+impl ConnectStats {
+    /// Add phase times to both cumulative and window stats
+    pub fn add_phase(&mut self, times: &PhaseTimes) {
+        self.phase.add(times);
+        self.window_phase.add(times);
+    }
+
+    /// Count why an established connection ended
+    pub fn add_end(&mut self, res: &Result<(), Error>, shutdown: bool) {
+        match res {
+            Err(Error::AliveTimeout) => self.close += 1,
+            Err(Error::Closed(frame)) => {
+                self.server_close += 1;
+                self.add_close_reason(frame);
+            }
+            _ if shutdown => self.close += 1,
+            Ok(()) => self.normal += 1,
+            Err(Error::IO(err) | Error::Ws(WsError::Io(err)))
+                if matches!(
+                    err.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::UnexpectedEof
+                ) =>
+            {
+                self.reset += 1
+            }
+            Err(Error::Ws(WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake))) => {
+                self.reset += 1
+            }
+            Err(Error::Ws(WsError::Protocol(_) | WsError::Utf8 | WsError::Capacity(_))) => {
+                self.protocol_error += 1
+            }
+            Err(_) => self.lost += 1,
+        }
+    }
+
+    /// Count close code with the truncated reason, only the code beyond the max count of reasons
+    fn add_close_reason(&mut self, frame: &Option<CloseFrame>) {
+        let code = frame.as_ref().map_or(1005, |frame| u16::from(frame.code));
+        let key = match frame {
+            Some(frame) if !frame.reason.is_empty() => {
+                let reason = frame
+                    .reason
+                    .chars()
+                    .take(MAX_REASON_LEN)
+                    .collect::<String>();
+                format!("{} {}", code, reason)
+            }
+            _ => code.to_string(),
+        };
+        let key =
+            if self.close_reasons.len() >= MAX_REASONS && !self.close_reasons.contains_key(&key) {
+                code.to_string()
+            } else {
+                key
+            };
+        *self.close_reasons.entry(key).or_default() += 1;
+    }
+
+    /// Display why established connections ended
+    pub fn ends(&self) -> String {
+        let reasons = self
+            .close_reasons
+            .iter()
+            .map(|(reason, num)| format!("{}: {}", reason, num))
+            .collect::<Vec<_>>();
+        format!(
+            "normal: {} close: {} server close: {} [{}] reset: {} protocol error: {} lost: {}",
+            self.normal,
+            self.close,
+            self.server_close,
+            reasons.join(", "),
+            self.reset,
+            self.protocol_error,
+            self.lost,
+        )
+    }
+
+    /// Update the tcp level bytes from the connection traffic
+    pub fn add_traffic(&mut self, traffic: &Traffic) {
+        self.sent = traffic.sent();
+        self.received = traffic.received();
+        self.compression = traffic.compression();
+    }
+
+    /// Reset measurements, keep the connection states and cumulative traffic
+    pub fn reset(&mut self) {
+        self.error = 0;
+        self.errors = ConnectErrors::default();
+        self.success_time = TimeStats::default();
+        self.phase = PhaseStats::default();
+        self.window_phase = PhaseStats::default();
+        self.lost = 0;
+        self.close = 0;
+        self.normal = 0;
+        self.server_close = 0;
+        self.close_reasons = BTreeMap::new();
+        self.reset = 0;
+        self.protocol_error = 0;
+        self.reconnect = 0;
+        self.reconnect_error = 0;
+        self.reconnect_time = TimeStats::default();
+    }
+}
+
+/// Ping pong stats of idle connections, pong not received before the next ping
+/// or the end of the connection is missing
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct PingStats {
+    /// num of ping sent
+    pub sent: usize,
+    /// num of pong received for the sent ping
+    pub pong: usize,
+    /// num of ping without pong
+    pub missing: usize,
+    /// round trip time of ping pong
+    pub rtt: TimeStats,
+}
+
+impl PingStats {
+    /// Count pong of the sent ping
+    pub fn add_pong(&mut self, rtt: Duration) {
+        self.pong += 1;
+        self.rtt.add(rtt);
+    }
+}
+
+impl fmt::Display for PingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent: {} pong: {} missing: {} rtt: [{}]",
+            self.sent, self.pong, self.missing, self.rtt
+        )
+    }
+}
+
+/// Idle probe stats, connections send nothing and wait for the server to close
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct IdleStats {
+    /// num of ping received from server
+    pub ping: usize,
+    /// num of connections received server ping
+    pub pinged: usize,
+    /// lifetime of connections ended by server
+    pub lifetime: TimeStats,
+}
+
+impl fmt::Display for IdleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "server ping: {} pinged connections: {} lifetime: [{}]",
+            self.ping, self.pinged, self.lifetime
+        )
+    }
+}
+
+/// Message payload bytes of the connections negotiated permessage-deflate
+#[derive(Default, Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct CompressionStats {
+    /// payload bytes sent before compression
+    pub sent: usize,
+    /// payload bytes sent after compression
+    pub compressed_sent: usize,
+    /// payload bytes received after decompression
+    pub received: usize,
+    /// payload bytes received before decompression
+    pub compressed_received: usize,
+}
+
+impl CompressionStats {
+    /// Bytes counted after the base
+    pub fn since(&self, base: &CompressionStats) -> Self {
+        Self {
+            sent: self.sent - base.sent,
+            compressed_sent: self.compressed_sent - base.compressed_sent,
+            received: self.received - base.received,
+            compressed_received: self.compressed_received - base.compressed_received,
+        }
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent: {}MB compressed: {}MB received: {}MB compressed: {}MB",
+            to_mb(self.sent as f64),
+            to_mb(self.compressed_sent as f64),
+            to_mb(self.received as f64),
+            to_mb(self.compressed_received as f64),
+        )
+    }
+}
+
+/// OK message stats, rejected events are counted by the machine-readable prefix
 #[derive(Default, Debug, Copy, Clone, Deserialize, Serialize)]
+pub struct OkStats {
+    /// num of accepted
+    pub accepted: usize,
+    /// num of rejected with `duplicate:`
+    pub duplicate: usize,
+    /// num of rejected with `blocked:`
+    pub blocked: usize,
+    /// num of rejected with `rate-limited:`
+    pub rate_limited: usize,
+    /// num of rejected with `invalid:`
+    pub invalid: usize,
+    /// num of rejected with `error:`
+    pub error: usize,
+    /// num of rejected without known prefix
+    pub other: usize,
+}
+
+impl OkStats {
+    /// Count OK message by status and message prefix
+    pub fn add(&mut self, status: bool, message: &str) {
+        if status {
+            self.accepted += 1;
+            return;
+        }
+        match message_prefix(message).as_deref() {
+            Some("duplicate") => self.duplicate += 1,
+            Some("blocked") => self.blocked += 1,
+            Some("rate-limited") => self.rate_limited += 1,
+            Some("invalid") => self.invalid += 1,
+            Some("error") => self.error += 1,
+            _ => self.other += 1,
+        }
+    }
+
+    /// Total OK messages received
+    pub fn total(&self) -> usize {
+        self.accepted
+            + self.duplicate
+            + self.blocked
+            + self.rate_limited
+            + self.invalid
+            + self.error
+            + self.other
+    }
+}
+
+impl fmt::Display for OkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "accepted: {} duplicate: {} blocked: {} rate-limited: {} invalid: {} error: {} other: {}",
+            self.accepted,
+            self.duplicate,
+            self.blocked,
+            self.rate_limited,
+            self.invalid,
+            self.error,
+            self.other,
+        )
+    }
+}
+
+/// Message stats
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct MessageStats {
     /// total event send
     pub total: usize,
@@ -159,32 +908,261 @@ pub struct MessageStats {
     pub error: usize,
     /// success event times stats
     pub success_time: TimeStats,
-    /// message transfer size as bytes
+    /// success event times stats since last print
+    #[serde(skip)]
+    pub window_time: TimeStats,
+    /// message transfer size as bytes, uncompressed
     pub size: usize,
+    /// message payload bytes sent
+    pub sent: usize,
+    /// message payload bytes received
+    pub received: usize,
     /// total event received
     pub event: usize,
+    /// OK messages of the sent events
+    pub ok: OkStats,
+    /// num of received message not expected, such as NOTICE or OK for unknown event
+    pub unexpected: usize,
+    /// num of events without OK in time, counted as completed with error
+    pub timeout: usize,
+    /// message payload bytes of the compressed connections, before and after compression
+    pub compression: CompressionStats,
+    /// num of rejected OK and NOTICE messages by reason, such as `ok:rate-limited`
+    pub reasons: BTreeMap<String, usize>,
+    /// start of the measurement, none when measured from the beginning
+    #[serde(skip)]
+    pub since: Option<time::Instant>,
 }
 
-/// Start bench
-pub async fn bench<F, Fut, P>(opts: BenchOpts, handler: F, mut printer: P)
+/// Count of top reasons display in text output
+const TOP_REASONS: usize = 3;
+
+/// Max count of distinct reasons, the new reasons are counted as `other` beyond
+const MAX_REASONS: usize = 64;
+
+/// Max length of the reason category
+const MAX_REASON_LEN: usize = 32;
+
+/// Get the machine-readable prefix of the OK or NOTICE message, such as `rate-limited`
+fn message_prefix(message: &str) -> Option<String> {
+    let (prefix, _) = message.split_once(':')?;
+    let prefix = prefix.trim();
+    if prefix.is_empty() || prefix.len() > MAX_REASON_LEN || prefix.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(prefix.to_lowercase())
+}
+
+/// Get reason category from the message prefix, or the leading words of the bare text
+///
+/// The words are lowercased and joined by `-` without numbers and punctuation,
+/// such as `too-many-requests` for `Too many requests (10/s)`
+fn reason_category(message: &str) -> String {
+    if let Some(prefix) = message_prefix(message) {
+        return prefix;
+    }
+    let mut category = String::new();
+    for word in message
+        .split_whitespace()
+        .filter(|word| !word.contains(|c: char| c.is_ascii_digit()))
+    {
+        let word = word
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .collect::<String>()
+            .to_lowercase();
+        if word.is_empty() {
+            continue;
+        }
+        let len = category.len() + usize::from(!category.is_empty()) + word.len();
+        if len > MAX_REASON_LEN {
+            break;
+        }
+        if !category.is_empty() {
+            category.push('-');
+        }
+        category.push_str(&word);
+    }
+    if category.is_empty() {
+        "other".to_owned()
+    } else {
+        category
+    }
+}
+
+impl MessageStats {
+    /// Count reason of the rejected OK or NOTICE message, kind as `ok` or `notice`
+    pub fn add_reason(&mut self, kind: &str, message: &str) {
+        let mut key = format!("{}:{}", kind, reason_category(message));
+        if self.reasons.len() >= MAX_REASONS && !self.reasons.contains_key(&key) {
+            key = format!("{}:other", kind);
+        }
+        *self.reasons.entry(key).or_default() += 1;
+    }
+
+    /// Format the most frequent reasons
+    pub fn top_reasons(&self, n: usize) -> String {
+        let mut reasons = self.reasons.iter().collect::<Vec<_>>();
+        reasons.sort_by(|a, b| b.1.cmp(a.1));
+        reasons
+            .iter()
+            .take(n)
+            .map(|(reason, count)| format!("{}: {}", reason, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Count payload bytes of the sent message
+    pub fn add_sent(&mut self, len: usize) {
+        self.sent += len;
+        self.size += len;
+    }
+
+    /// Count payload bytes of the received message
+    pub fn add_received(&mut self, len: usize) {
+        self.received += len;
+        self.size += len;
+    }
+
+    /// Whether the request sent at the time is measured, requests sent in the warm-up are dropped
+    pub fn measured(&self, sent: time::Instant) -> bool {
+        self.since.is_none_or(|since| sent >= since)
+    }
+
+    /// Count completed request sent at the time, drop the request sent in the warm-up
+    pub fn add_complete(&mut self, sent: time::Instant) {
+        if self.measured(sent) {
+            self.complete += 1;
+            self.add_success_time(sent.elapsed());
+        }
+    }
+
+    /// Count the request sent at the time as timed out, drop the request sent in the warm-up
+    pub fn add_timeout(&mut self, sent: time::Instant) {
+        if self.measured(sent) {
+            self.complete += 1;
+            self.error += 1;
+            self.timeout += 1;
+        }
+    }
+
+    /// Add success time to both cumulative and window stats
+    pub fn add_success_time(&mut self, time: Duration) {
+        self.success_time.add(time);
+        self.window_time.add(time);
+    }
+}
+
+/// Bench result when finished or interrupted
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    /// total run time
+    pub elapsed: Duration,
+    /// final connect stats
+    pub stats: ConnectStats,
+    /// stopped by ctrl-c
+    pub interrupted: bool,
+    /// connect stats of the warm-up phase
+    pub warmup: Option<ConnectStats>,
+}
+
+impl BenchResult {
+    /// Run time excluding the warm-up phase
+    pub fn measured(&self, warmup: u64) -> Duration {
+        if self.warmup.is_some() {
+            self.elapsed.saturating_sub(Duration::from_secs(warmup))
+        } else {
+            self.elapsed
+        }
+    }
+
+    /// Tcp level bytes sent and received excluding the warm-up phase
+    pub fn wire(&self) -> (usize, usize) {
+        let (sent, received) = self
+            .warmup
+            .as_ref()
+            .map_or((0, 0), |w| (w.sent, w.received));
+        (self.stats.sent - sent, self.stats.received - received)
+    }
+}
+
+pub fn to_mb(bytes: f64) -> f64 {
+    (((bytes / 100000.0) as u64) as f64) / 10.0
+}
+
+/// Transfer rate as MB/s, 0 when the duration is too short
+pub fn rate(bytes: usize, elapsed: Duration) -> f64 {
+    if elapsed.as_secs() > 1 {
+        to_mb(bytes as f64 / elapsed.as_secs_f64())
+    } else {
+        0.0
+    }
+}
+
+/// Start bench, run until all connections complete, duration expired or ctrl-c received
+///
+/// The `on_warmup` is called with the connect stats reset when the warm-up ends,
+/// reset the other stats there to keep the same boundary
+///
+/// Return error when the TLS options are invalid, such as missing or bad PEM files
+pub async fn bench<F, Fut, P, W>(
+    opts: BenchOpts,
+    handler: F,
+    mut printer: P,
+    on_warmup: W,
+) -> Result<BenchResult, Error>
 where
-    F: FnOnce(WebSocketStream<MaybeTlsStream<TcpStream>>) -> Fut + Send + Sync + Clone + 'static,
+    F: FnOnce(WsStream, Shutdown) -> Fut + Send + Sync + Clone + 'static,
     Fut: Future<Output = Result<(), Error>> + Send + 'static,
     P: FnMut(time::Instant, &ConnectStats) + Send + 'static,
+    W: FnOnce(&ConnectStats) + Send + 'static,
 {
-    let connaddr = Some(parse_wsaddr(&opts.url).unwrap());
+    let tls = Tls::new(&opts.connection, &opts.url).map_err(Error::Tls)?;
+    // resolve once for all connections, retry in each connection when failed,
+    // the dns phase is only measured in the retry
+    let dial_url = opts.connection.dial_url(&opts.url);
+    let connaddrs = resolve_wsaddrs(dial_url, &opts.connection.resolve)
+        .await
+        .unwrap_or_default();
+    let traffic = Arc::new(Traffic::default());
+    let c_traffic = traffic.clone();
+    let p_traffic = traffic.clone();
+    let connection = Arc::new(opts.connection.clone());
+    let printed = Arc::new(AtomicBool::new(false));
     let stats = Arc::new(Mutex::new(ConnectStats {
         total: opts.count,
         ..Default::default()
     }));
     let c_stats = stats.clone();
+    let p_stats = stats.clone();
+    let now = time::Instant::now();
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let shutdown = Shutdown(shutdown);
+    let duration = opts.duration;
 
-    let run_print = tokio::spawn(async move {
-        let now = time::Instant::now();
+    let warmup_stats = Arc::new(Mutex::new(None));
+    if opts.warmup > 0 {
+        let stats = stats.clone();
+        let warmup_stats = warmup_stats.clone();
+        let warmup = opts.warmup;
+        let traffic = traffic.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_secs(warmup)).await;
+            let mut r = stats.lock();
+            r.add_traffic(&traffic);
+            *warmup_stats.lock() = Some(r.clone());
+            r.reset();
+            on_warmup(&r);
+        });
+    }
+
+    let mut run_print = tokio::spawn(async move {
         loop {
             {
-                let r = stats.lock();
+                let mut r = p_stats.lock();
+                r.add_traffic(&p_traffic);
                 printer(now, r.deref());
+                r.window_phase = PhaseStats::default();
                 if r.complete == r.total {
                     break;
                 }
@@ -193,126 +1171,408 @@ where
         }
     });
 
-    let run_connect = tokio::spawn(async move {
-        let interfaces = opts.interface.unwrap_or_default();
-        let len = interfaces.len();
+    let mut run_connect = tokio::spawn(async move {
+        let interfaces = Arc::new(Interfaces::new(opts.interface.unwrap_or_default()));
         let start_time = time::Instant::now();
         let mut tasks = vec![];
         for i in 0..opts.count {
+            if shutdown.is_shutdown() {
+                break;
+            }
+            let mut c_shutdown = shutdown.clone();
             let url = opts.url.clone();
             let stats = c_stats.clone();
-            let interface = if len > 0 {
-                Some(interfaces[i % len])
-            } else {
-                None
-            };
+            let connaddr = (!connaddrs.is_empty()).then(|| connection.balance.pick(&connaddrs, i));
+            let interfaces = interfaces.clone();
             let handler = handler.clone();
+            let tls = tls.clone();
+            let connection = connection.clone();
+            let printed = printed.clone();
+            let traffic = c_traffic.clone();
             let task = tokio::spawn(async move {
                 add1!(stats, connect);
-                let now = time::Instant::now();
-                let res = connect(url, interface, connaddr).await;
-                {
-                    let mut r = stats.lock();
-                    r.time = start_time.elapsed();
-                }
-                match res {
-                    Ok(stream) => {
-                        {
-                            let mut r = stats.lock();
-                            r.alive += 1;
-                            r.success_time = r.success_time.add(now.elapsed());
-                        }
+                let mut backoff = Backoff::new(
+                    Duration::from_millis(connection.reconnect_min),
+                    Duration::from_millis(connection.reconnect_max),
+                    connection.reconnect_jitter,
+                );
+                // time when the connection lost, reconnecting when set
+                let mut lost_at: Option<time::Instant> = None;
+                loop {
+                    let now = time::Instant::now();
+                    let res = async {
+                        let (connaddr, dns) = match connaddr {
+                            Some(addr) => (addr, None),
+                            None => {
+                                let dial_url = connection.dial_url(&url);
+                                let addrs = resolve_wsaddrs(dial_url, &connection.resolve)
+                                    .await
+                                    .map_err(Error::Dns)?;
+                                (connection.balance.pick(&addrs, i), Some(now.elapsed()))
+                            }
+                        };
+                        let interface = interfaces.get(&connaddr, i)?;
+                        let (stream, response, times) = connect(
+                            url.clone(),
+                            interface,
+                            Some(connaddr),
+                            &connection,
+                            tls.as_ref(),
+                            &traffic,
+                        )
+                        .await?;
+                        Ok::<_, Error>((stream, response, PhaseTimes { dns, ..times }))
+                    }
+                    .await;
+                    {
+                        let mut r = stats.lock();
+                        r.time = start_time.elapsed();
+                    }
+                    match res {
+                        Ok((stream, response, times)) => {
+                            if connection.verbose && !printed.swap(true, Ordering::Relaxed) {
+                                print_response(&response);
+                            }
+                            {
+                                let mut r = stats.lock();
+                                r.alive += 1;
+                                if stream.get_ref().is_negotiated() {
+                                    r.deflate += 1;
+                                }
+                                r.success_time.add(now.elapsed());
+                                r.add_phase(&times);
+                                if let Some(lost_at) = lost_at.take() {
+                                    r.reconnect += 1;
+                                    r.reconnect_time.add(lost_at.elapsed());
+                                }
+                            }
+                            backoff.reset();
 
-                        let res = keepalive(opts.keepalive, handler(stream)).await;
-                        subtract1!(stats, alive);
-                        if let Err(Error::AliveTimeout) = res {
-                            add1!(stats, close);
-                        } else {
-                            add1!(stats, lost);
+                            let handler = handler.clone();
+                            let res =
+                                keepalive(opts.keepalive, handler(stream, c_shutdown.clone()))
+                                    .await;
+                            let shutdown = c_shutdown.is_shutdown();
+                            {
+                                let mut r = stats.lock();
+                                r.alive -= 1;
+                                r.add_end(&res, shutdown);
+                            }
+                            // only reconnect when lost
+                            if !connection.reconnect
+                                || shutdown
+                                || matches!(res, Ok(()) | Err(Error::AliveTimeout))
+                            {
+                                break;
+                            }
+                            lost_at = Some(time::Instant::now());
+                        }
+                        Err(err) => {
+                            let mut r = stats.lock();
+                            let sample = connection.error_sample;
+                            if lost_at.is_some() {
+                                // keep retrying, the connection is counted as lost already
+                                r.reconnect_error += 1;
+                                if sample > 0 && (r.reconnect_error - 1) % sample == 0 {
+                                    eprintln!("reconnect error: {}", err);
+                                }
+                            } else {
+                                if !err.is_timeout() {
+                                    r.error += 1;
+                                }
+                                r.errors.add(&err);
+                                if sample > 0 && (r.errors.total() - 1) % sample == 0 {
+                                    eprintln!("connect error: {}", err);
+                                }
+                                break;
+                            }
                         }
                     }
-                    Err(_err) => {
-                        // println!("error {:?}", _err);
-                        add1!(stats, error);
+                    tokio::select! {
+                        _ = time::sleep(backoff.next_delay()) => {}
+                        _ = c_shutdown.wait() => break,
                     }
                 }
                 add1!(stats, complete);
             });
             tasks.push(task);
             if (i + 1) % opts.rate == 0 {
-                time::sleep(Duration::from_secs(1)).await;
+                let mut shutdown = shutdown.clone();
+                tokio::select! {
+                    _ = time::sleep(Duration::from_secs(1)) => {}
+                    _ = shutdown.wait() => {}
+                }
             }
         }
         join_all(tasks).await;
     });
-    select(run_print, run_connect).await;
+
+    let deadline = async move {
+        if duration == 0 {
+            pending::<()>().await;
+        } else {
+            time::sleep(Duration::from_secs(duration)).await;
+        }
+    };
+    let mut interrupted = false;
+    let stop = tokio::select! {
+        _ = &mut run_print => false,
+        _ = &mut run_connect => false,
+        _ = deadline => true,
+        _ = signal::ctrl_c() => {
+            interrupted = true;
+            true
+        }
+    };
+    if stop {
+        // stop spawning and close all connections, wait for in-flight requests
+        let _ = shutdown_tx.send(true);
+        tokio::select! {
+            _ = time::timeout(SHUTDOWN_GRACE, run_connect) => {}
+            // force stop when ctrl-c pressed again
+            _ = signal::ctrl_c() => {}
+        }
+    }
+    run_print.abort();
+    let stats = {
+        let mut r = stats.lock();
+        r.add_traffic(&traffic);
+        r.clone()
+    };
+    let warmup = warmup_stats.lock().take();
+    Ok(BenchResult {
+        elapsed: now.elapsed(),
+        stats,
+        interrupted,
+        warmup,
+    })
 }
 
-/// Start bench with message stats
+/// Start bench with message stats, print summary when finished
 pub async fn bench_message<F, Fut>(
     opts: BenchOpts,
     stats: Arc<Mutex<MessageStats>>,
     json: bool,
     handler: F,
-) where
-    F: FnOnce(WebSocketStream<MaybeTlsStream<TcpStream>>) -> Fut + Send + Sync + Clone + 'static,
+) -> Result<BenchResult, Error>
+where
+    F: FnOnce(WsStream, Shutdown) -> Fut + Send + Sync + Clone + 'static,
     Fut: core::future::Future<Output = Result<(), Error>> + Send + 'static,
 {
+    let warmup = opts.warmup;
+    let warmup_stats = Arc::new(Mutex::new(None));
+    let w_stats = stats.clone();
+    let w_warmup_stats = warmup_stats.clone();
+    // compression bytes are counted by the connections, measured from the warm-up end
+    let compression_base = Arc::new(Mutex::new(CompressionStats::default()));
+    let w_compression_base = compression_base.clone();
+    let on_warmup = move |cstats: &ConnectStats| {
+        let mut st = w_stats.lock();
+        st.compression = cstats.compression.since(&w_compression_base.lock());
+        let measured = MessageStats {
+            since: Some(time::Instant::now()),
+            ..Default::default()
+        };
+        *w_warmup_stats.lock() = Some(std::mem::replace(&mut *st, measured));
+        *w_compression_base.lock() = cstats.compression;
+    };
+
     let mut last_count: usize = 0;
     let mut last_size: usize = 0;
+    let mut last_sent: usize = 0;
+    let mut last_received: usize = 0;
+    let mut last_wire = (0, 0);
     let mut last_time = time::Instant::now();
-    bench(opts, handler, move |now, cstats| {
-        let st = stats.lock();
-        let cur_count = st.complete - st.error - last_count;
+    let p_stats = stats.clone();
+    let p_warmup_stats = warmup_stats.clone();
+    let p_compression_base = compression_base.clone();
+    let printer = move |now: time::Instant, cstats: &ConnectStats| {
+        let mut st = p_stats.lock();
+        st.compression = cstats.compression.since(&p_compression_base.lock());
+        let window = std::mem::take(&mut st.window_time);
+        let in_warmup = now.elapsed().as_secs() < warmup;
+        // stats are reset when warm-up ends, keep counting from the warm-up stats
+        let (warmup_count, warmup_size, warmup_sent, warmup_received) =
+            match p_warmup_stats.lock().deref() {
+                Some(w) => (w.complete - w.error, w.size, w.sent, w.received),
+                None => (0, 0, 0, 0),
+            };
+        let count = warmup_count + st.complete - st.error;
+        let total_size = warmup_size + st.size;
+        let total_sent = warmup_sent + st.sent;
+        let total_received = warmup_received + st.received;
+        let cur_count = count - last_count;
         let tps = if last_time.elapsed().as_secs() > 1 {
             cur_count as f64 / last_time.elapsed().as_secs_f64()
         } else {
             0.0
         };
 
-        let cur_size = st.size - last_size;
-        let size = if last_time.elapsed().as_secs() > 1 {
-            cur_size as f64 / last_time.elapsed().as_secs_f64()
-        } else {
-            0.0
-        };
+        let elapsed = last_time.elapsed();
         let tps = tps as u64;
-        let size = (((size / 100000.0) as u64) as f64) / 10.0;
+        let size = rate(total_size - last_size, elapsed);
+        let sent = rate(total_sent - last_sent, elapsed);
+        let received = rate(total_received - last_received, elapsed);
+        let wire_sent = rate(cstats.sent - last_wire.0, elapsed);
+        let wire_received = rate(cstats.received - last_wire.1, elapsed);
 
         if json {
             let json = serde_json::json!({
                 "elapsed": now.elapsed().as_millis(),
+                "warmup": in_warmup,
                 "last_elapsed": last_time.elapsed().as_millis(),
                 "tps": tps,
                 "size": size,
+                "sent_mb": sent,
+                "received_mb": received,
+                "wire_sent_mb": wire_sent,
+                "wire_received_mb": wire_received,
                 "connect_stats": cstats,
                 "message_stats": st.deref(),
+                "window_time": window,
+                "window_phase": cstats.window_phase,
             });
             println!("{}", serde_json::to_string(&json).unwrap());
         } else {
-            let time = st.success_time;
-            let time = format!(
-                "avg: {}ms max: {}ms min: {}ms",
-                time.avg.as_millis(),
-                time.max.as_millis(),
-                time.min.as_millis(),
-            );
-            let message = format!(
-                "tps: {}/s transfer: {}MB/s complate: {} event: {} error: {} time: [{}]",
-                tps, size, st.complete, st.event, st.error, time,
+            let mut message = format!(
+                "tps: {}/s transfer: [sent: {}MB/s received: {}MB/s] wire: [sent: {}MB/s received: {}MB/s] complate: {} event: {} error: {} time: [{}] window time: [{}]",
+                tps,
+                sent,
+                received,
+                wire_sent,
+                wire_received,
+                st.complete,
+                st.event,
+                st.error,
+                st.success_time,
+                window,
             );
+            if !st.reasons.is_empty() {
+                message.push_str(&format!(" reasons: [{}]", st.top_reasons(TOP_REASONS)));
+            }
             println!(
-                "elapsed: {}ms connections: {} message {}",
+                "{}elapsed: {}ms connections: {} phase: [{}] message {}",
+                if in_warmup { "warmup " } else { "" },
                 now.elapsed().as_millis(),
                 cstats.alive,
+                cstats.window_phase,
                 message,
             );
         }
-        last_count = st.complete - st.error;
-        last_size = st.size;
+        last_count = count;
+        last_size = total_size;
+        last_sent = total_sent;
+        last_received = total_received;
+        last_wire = (cstats.sent, cstats.received);
         last_time = time::Instant::now();
-    })
-    .await;
+    };
+    let result = bench(opts, handler, printer, on_warmup).await?;
+
+    {
+        let mut st = stats.lock();
+        st.compression = result.stats.compression.since(&compression_base.lock());
+        let warmup_st = warmup_stats.lock().take();
+        let measured = result.measured(warmup);
+        let elapsed = measured.as_secs_f64();
+        let tps = ((st.complete - st.error) as f64 / elapsed) as u64;
+        let size = to_mb(st.size as f64);
+        let transfer = to_mb(st.size as f64 / elapsed);
+        let cstats = &result.stats;
+        let (wire_sent, wire_received) = result.wire();
+        if json {
+            let json = serde_json::json!({
+                "summary": {
+                    "elapsed": result.elapsed.as_millis(),
+                    "measured": measured.as_millis(),
+                    "interrupted": result.interrupted,
+                    "tps": tps,
+                    "size_mb": size,
+                    "transfer_mb_per_sec": transfer,
+                    "sent_mb": to_mb(st.sent as f64),
+                    "received_mb": to_mb(st.received as f64),
+                    "wire_sent_mb": to_mb(wire_sent as f64),
+                    "wire_received_mb": to_mb(wire_received as f64),
+                    "connect_stats": cstats,
+                    "message_stats": st.deref(),
+                    "warmup": warmup_st.as_ref().map(|warmup_st| serde_json::json!({
+                        "connect_stats": result.warmup,
+                        "message_stats": warmup_st,
+                    })),
+                }
+            });
+            println!("{}", serde_json::to_string(&json).unwrap());
+        } else {
+            if let (Some(wst), Some(wcstats)) = (&warmup_st, &result.warmup) {
+                println!(
+                    "warmup elapsed: {}ms connect error: {} connect time: [{}] complate: {} event: {} error: {} time: [{}]",
+                    Duration::from_secs(warmup).as_millis(),
+                    wcstats.error,
+                    wcstats.success_time,
+                    wst.complete,
+                    wst.event,
+                    wst.error,
+                    wst.success_time,
+                );
+            }
+            println!(
+                "summary elapsed: {}ms interrupted: {} connections: {} connect error: {} connect timeout: {} handshake timeout: {} connect time: [{}]",
+                result.elapsed.as_millis(),
+                result.interrupted,
+                cstats.connect,
+                cstats.error,
+                cstats.errors.connect_timeout,
+                cstats.errors.handshake_timeout,
+                cstats.success_time,
+            );
+            println!("summary end {}", cstats.ends());
+            if cstats.reconnect > 0 || cstats.reconnect_error > 0 {
+                println!(
+                    "summary reconnect: {} error: {} time: [{}]",
+                    cstats.reconnect, cstats.reconnect_error, cstats.reconnect_time
+                );
+            }
+            if cstats.errors.total() > 0 {
+                println!("summary connect errors: [{}]", cstats.errors);
+            }
+            for (name, time) in cstats.phase.iter() {
+                println!("summary {} time: [{}]", name, time);
+            }
+            if cstats.deflate > 0 {
+                println!(
+                    "summary deflate connections: {} {}",
+                    cstats.deflate, st.compression
+                );
+            }
+            println!(
+                "summary message tps: {}/s transfer: {}MB {}MB/s complate: {} event: {} error: {} time: [{}]",
+                tps, size, transfer, st.complete, st.event, st.error, st.success_time,
+            );
+            println!(
+                "summary transfer sent: {}MB {}MB/s received: {}MB {}MB/s",
+                to_mb(st.sent as f64),
+                to_mb(st.sent as f64 / elapsed),
+                to_mb(st.received as f64),
+                to_mb(st.received as f64 / elapsed),
+            );
+            println!(
+                "summary wire sent: {}MB {}MB/s received: {}MB {}MB/s",
+                to_mb(wire_sent as f64),
+                to_mb(wire_sent as f64 / elapsed),
+                to_mb(wire_received as f64),
+                to_mb(wire_received as f64 / elapsed),
+            );
+            if st.ok.total() > 0 || st.unexpected > 0 || st.timeout > 0 {
+                println!(
+                    "summary ok {} unexpected: {} timeout: {}",
+                    st.ok, st.unexpected, st.timeout
+                );
+            }
+            if !st.reasons.is_empty() {
+                println!("summary reasons: [{}]", st.top_reasons(TOP_REASONS));
+            }
+        }
+    }
+    Ok(result)
 }
 
 /// Connect websocket server with bind interface address
@@ -320,22 +1580,122 @@ pub async fn connect(
     url: Url,
     interface: Option<SocketAddr>,
     connaddr: Option<SocketAddr>,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
+    opts: &ConnectionOpts,
+    tls: Option<&Tls>,
+    traffic: &Arc<Traffic>,
+) -> Result<(WsStream, Response, PhaseTimes), Error> {
+    let mut times = PhaseTimes::default();
     let connaddr = match connaddr {
         Some(addr) => addr,
-        None => parse_wsaddr(&url)?,
+        None => {
+            let now = time::Instant::now();
+            let addr = parse_wsaddr(opts.dial_url(&url))
+                .await
+                .map_err(Error::Dns)?;
+            times.dns = Some(now.elapsed());
+            addr
+        }
     };
 
-    let socket = TcpSocket::new_v4()?;
+    let socket = if connaddr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
     if let Some(addr) = interface {
         socket.bind(addr)?;
     }
-    let tcp = socket.connect(connaddr).await?;
+    let now = time::Instant::now();
+    let tcp = timeout(opts.connect_timeout, Error::ConnectTimeout, async {
+        Ok(socket.connect(connaddr).await?)
+    })
+    .await?;
+    times.tcp = now.elapsed();
+    let mut tcp = CountingStream::new(tcp, traffic.clone());
 
-    let (stream, _) = time::timeout(Duration::from_secs(60), client_async_tls(url, tcp))
-        .await
-        .map_err(|_| Error::ConnectTimeout)??;
-    Ok(stream)
+    if let Some(proxy) = &opts.proxy {
+        let now = time::Instant::now();
+        timeout(opts.connect_timeout, Error::ProxyTimeout, async {
+            proxy.tunnel(&mut tcp, &url).await.map_err(Error::Proxy)
+        })
+        .await?;
+        times.proxy = Some(now.elapsed());
+    }
+
+    let mut request = url.into_client_request()?;
+    let generated = request.headers().clone();
+    for (name, value) in &opts.header {
+        // replace the generated websocket headers, keep repeated custom headers
+        if generated.contains_key(name) {
+            request.headers_mut().insert(name, value.clone());
+        } else {
+            request.headers_mut().append(name, value.clone());
+        }
+    }
+    let deflate = opts.deflate();
+    if let Some(deflate) = &deflate {
+        let offer = HeaderValue::from_str(&deflate.offer()).expect("valid header value");
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_EXTENSIONS, offer);
+    }
+
+    let (stream, response) = timeout(opts.handshake_timeout, Error::HandshakeTimeout, async {
+        let stream = match tls {
+            Some(tls) => {
+                let now = time::Instant::now();
+                let stream = tls.connect(tcp).await.map_err(Error::Tls)?;
+                times.tls = Some(now.elapsed());
+                MaybeTlsStream::Rustls(stream)
+            }
+            None => MaybeTlsStream::Plain(tcp),
+        };
+        let stream = DeflateStream::new(stream, deflate, traffic.clone());
+        let now = time::Instant::now();
+        let res = client_async(request, stream).await?;
+        times.upgrade = now.elapsed();
+        Ok(res)
+    })
+    .await?;
+    Ok((stream, response, times))
+}
+
+/// Print response status and headers like curl verbose
+fn print_response(response: &Response) {
+    eprintln!("< {:?} {}", response.version(), response.status());
+    for (name, value) in response.headers() {
+        eprintln!("< {}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+}
+
+/// Return the given error when timeout, ignore when second is 0
+async fn timeout<T, F: Future<Output = Result<T, Error>>>(
+    second: u64,
+    err: Error,
+    fut: F,
+) -> Result<T, Error> {
+    if second == 0 {
+        fut.await
+    } else {
+        time::timeout(Duration::from_secs(second), fut)
+            .await
+            .map_err(|_| err)?
+    }
+}
+
+/// Send close frame and wait for the server to close the connection
+pub async fn close<W, R>(write: &mut W, read: &mut R) -> Result<(), Error>
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    write.send(Message::Close(None)).await?;
+    while let Some(msg) = read.next().await {
+        if msg?.is_close() {
+            break;
+        }
+    }
+    Ok(())
 }
 
 /// Return AliveTimeout error when timeout
@@ -343,13 +1703,282 @@ pub async fn keepalive<T: Future<Output = Result<(), Error>>>(
     second: u64,
     stay: T,
 ) -> Result<(), Error> {
-    let result = if second == 0 {
-        Ok(stay.await)
-    } else {
-        time::timeout(Duration::from_secs(second), stay)
-            .await
-            .map_err(|_| Error::AliveTimeout)
+    timeout(second, Error::AliveTimeout, stay).await
+}
+
+/// Close code and reason, 1005 means no status code received
+fn close_reason(frame: &Option<CloseFrame>) -> String {
+    match frame {
+        Some(frame) if frame.reason.is_empty() => u16::from(frame.code).to_string(),
+        Some(frame) => format!("{} {}", u16::from(frame.code), frame.reason),
+        None => "1005".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConnectErrors, ConnectStats, Error, IdleStats, MessageStats, OkStats, PhaseStats,
+        PhaseTimes, PingStats, TimeStats, MAX_REASONS, MAX_REASON_LEN,
     };
-    result?.map_err(|_| Error::Lost)?;
-    Ok(())
+    use std::io;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::{
+        error::ProtocolError,
+        http::Response,
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error as WsError,
+    };
+
+    #[test]
+    fn time_stats() {
+        let mut stats = TimeStats::default();
+        for i in 1..=100 {
+            stats.add(Duration::from_millis(i));
+        }
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(100));
+        let p = stats.percentiles();
+        assert_eq!(p.p50.as_millis(), 50);
+        assert_eq!(p.p99.as_millis(), 99);
+
+        let mut other = TimeStats::default();
+        other.add(Duration::from_millis(200));
+        stats.merge(&other);
+        assert_eq!(stats.count, 101);
+        assert_eq!(stats.max, Duration::from_millis(200));
+        assert_eq!(stats.percentiles().p9999.as_millis(), 200);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        let p50 = json["percentiles"]["p50"].as_f64().unwrap();
+        assert!((50.0..52.0).contains(&p50));
+
+        // sub milli second latency keeps the precision
+        let mut stats = TimeStats::default();
+        stats.add(Duration::from_micros(250));
+        assert!(stats.to_string().starts_with("avg: 0.25ms max: 0.25ms"));
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["avg"], 0.25);
+    }
+
+    #[test]
+    fn phase_stats() {
+        let mut stats = PhaseStats::default();
+        stats.add(&PhaseTimes {
+            dns: None,
+            tcp: Duration::from_millis(2),
+            proxy: None,
+            tls: Some(Duration::from_millis(10)),
+            upgrade: Duration::from_millis(4),
+        });
+        stats.add(&PhaseTimes {
+            tcp: Duration::from_millis(4),
+            upgrade: Duration::from_millis(6),
+            ..Default::default()
+        });
+        assert_eq!(stats.tcp.count, 2);
+        assert_eq!(stats.tcp.avg, Duration::from_millis(3));
+        assert_eq!(stats.tls.count, 1);
+        let names = stats.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["tcp", "tls", "upgrade"]);
+        assert_eq!(
+            stats.to_string(),
+            "dns: 0.00ms tcp: 3.00ms proxy: 0.00ms tls: 10.00ms upgrade: 5.00ms"
+        );
+        let mut connect = ConnectStats::default();
+        connect.add_phase(&PhaseTimes {
+            dns: Some(Duration::from_millis(1)),
+            ..Default::default()
+        });
+        assert_eq!(connect.phase.dns.count, 1);
+        assert_eq!(connect.window_phase.dns.count, 1);
+        connect.reset();
+        assert_eq!(connect.phase.dns.count, 0);
+        assert_eq!(connect.window_phase.dns.count, 0);
+    }
+
+    #[test]
+    fn connect_errors() {
+        let mut errors = ConnectErrors::default();
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        errors.add(&Error::IO(refused));
+        errors.add(&Error::IO(io::Error::from(io::ErrorKind::AddrInUse)));
+        errors.add(&Error::IO(io::Error::from(io::ErrorKind::AddrNotAvailable)));
+        errors.add(&Error::Dns(io::Error::from(io::ErrorKind::NotFound)));
+        errors.add(&Error::ConnectTimeout);
+        errors.add(&Error::ProxyTimeout);
+        for _ in 0..2 {
+            let res = Response::builder().status(503).body(None).unwrap();
+            errors.add(&Error::Ws(WsError::Http(res)));
+        }
+        errors.add(&Error::IO(io::Error::from(io::ErrorKind::Other)));
+        // kernel syn timeout without --connect-timeout
+        let timed_out = Error::Ws(WsError::Io(io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(timed_out.is_timeout());
+        errors.add(&timed_out);
+        assert!(Error::ProxyTimeout.is_timeout());
+        assert!(!Error::IO(io::Error::from(io::ErrorKind::ConnectionRefused)).is_timeout());
+        assert!(!Error::Dns(io::Error::from(io::ErrorKind::TimedOut)).is_timeout());
+        assert_eq!(errors.refused, 1);
+        assert_eq!(errors.addr_in_use, 1);
+        assert_eq!(errors.addr_not_available, 1);
+        assert_eq!(errors.status[&503], 2);
+        assert_eq!(errors.proxy_timeout, 1);
+        assert_eq!(errors.connect_timeout, 2);
+        assert_eq!(errors.total(), 10);
+        assert_eq!(
+            errors.to_string(),
+            "dns: 1, refused: 1, addr in use: 1, addr not available: 1, connect timeout: 2, proxy timeout: 1, status 503: 2, other: 1"
+        );
+    }
+
+    #[test]
+    fn connect_ends() {
+        let mut stats = ConnectStats::default();
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "rate limited".into(),
+        };
+        stats.add_end(&Err(Error::Closed(Some(frame))), false);
+        stats.add_end(&Err(Error::Closed(None)), true);
+        stats.add_end(&Err(Error::AliveTimeout), false);
+        let lost = || Error::IO(io::Error::from(io::ErrorKind::Other));
+        stats.add_end(&Err(lost()), true);
+        stats.add_end(&Ok(()), false);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        stats.add_end(&Err(Error::Ws(WsError::Io(reset))), false);
+        let eof = WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake);
+        stats.add_end(&Err(Error::Ws(eof)), false);
+        let protocol = WsError::Protocol(ProtocolError::InvalidOpcode(3));
+        stats.add_end(&Err(Error::Ws(protocol)), false);
+        stats.add_end(&Err(lost()), false);
+        assert_eq!(
+            stats.ends(),
+            "normal: 1 close: 2 server close: 2 [1005: 1, 1008 rate limited: 1] reset: 2 protocol error: 1 lost: 1"
+        );
+
+        let mut stats = ConnectStats::default();
+        let close = |reason: String| {
+            Error::Closed(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: reason.into(),
+            }))
+        };
+        stats.add_end(&Err(close("x".repeat(100))), false);
+        let truncated = format!("1001 {}", "x".repeat(MAX_REASON_LEN));
+        assert_eq!(stats.close_reasons[&truncated], 1);
+        for i in 0..MAX_REASONS + 1 {
+            stats.add_end(&Err(close(format!("reason {}", i))), false);
+        }
+        // the code is added beyond the cap
+        assert_eq!(stats.close_reasons.len(), MAX_REASONS + 1);
+        assert_eq!(stats.close_reasons["1001"], 2);
+
+        // the warm-up reset keeps the connection states only
+        stats.alive = 2;
+        stats.reconnect = 1;
+        stats.reconnect_error = 1;
+        stats.reset();
+        assert_eq!(
+            stats.ends(),
+            "normal: 0 close: 0 server close: 0 [] reset: 0 protocol error: 0 lost: 0"
+        );
+        assert_eq!((stats.reconnect, stats.reconnect_error), (0, 0));
+        assert_eq!(stats.alive, 2);
+    }
+
+    #[test]
+    fn ok_stats() {
+        let mut stats = OkStats::default();
+        stats.add(true, "");
+        stats.add(true, "duplicate: already have this event");
+        stats.add(false, "duplicate: already have this event");
+        stats.add(false, "rate-limited: slow down");
+        stats.add(false, "blocked: you are banned");
+        stats.add(false, "invalid: bad signature");
+        stats.add(false, "Error: could not save");
+        stats.add(false, "unknown reason");
+        assert_eq!(stats.accepted, 2);
+        assert_eq!(stats.duplicate, 1);
+        assert_eq!(stats.rate_limited, 1);
+        assert_eq!(stats.blocked, 1);
+        assert_eq!(stats.invalid, 1);
+        assert_eq!(stats.error, 1);
+        assert_eq!(stats.other, 1);
+        assert_eq!(stats.total(), 8);
+    }
+
+    #[test]
+    fn ping_stats() {
+        let mut stats = PingStats {
+            sent: 3,
+            missing: 1,
+            ..Default::default()
+        };
+        stats.add_pong(Duration::from_millis(4));
+        stats.add_pong(Duration::from_millis(2));
+        assert_eq!(stats.pong, 2);
+        assert_eq!(stats.rtt.avg, Duration::from_millis(3));
+        assert!(stats
+            .to_string()
+            .starts_with("sent: 3 pong: 2 missing: 1 rtt: [avg: 3.00ms"));
+    }
+
+    #[test]
+    fn idle_stats() {
+        let mut stats = IdleStats {
+            ping: 2,
+            pinged: 1,
+            ..Default::default()
+        };
+        stats.lifetime.add(Duration::from_secs(60));
+        assert!(stats
+            .to_string()
+            .starts_with("server ping: 2 pinged connections: 1 lifetime: [avg: 60000.00ms"));
+    }
+
+    #[test]
+    fn warmup_boundary() {
+        let since = tokio::time::Instant::now();
+        let mut stats = MessageStats {
+            since: Some(since),
+            ..Default::default()
+        };
+        stats.add_complete(since - Duration::from_millis(10));
+        assert_eq!(stats.complete, 0);
+        assert_eq!(stats.success_time.count, 0);
+        stats.add_complete(since);
+        assert_eq!(stats.complete, 1);
+        assert_eq!(stats.success_time.count, 1);
+        assert!(MessageStats::default().measured(since - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn reasons() {
+        let mut stats = MessageStats::default();
+        stats.add_reason("ok", "rate-limited: slow down");
+        stats.add_reason("ok", "rate-limited: slow down");
+        stats.add_reason("ok", "");
+        stats.add_reason("notice", "Error: could not parse message");
+        stats.add_reason("notice", "Too many requests (10/s), try again later");
+        stats.add_reason("notice", "too many requests (20/s), try again later");
+        stats.add_reason(
+            "notice",
+            "something went wrong at the relay, please retry later",
+        );
+        assert_eq!(stats.reasons["ok:rate-limited"], 2);
+        assert_eq!(stats.reasons["ok:other"], 1);
+        assert_eq!(stats.reasons["notice:error"], 1);
+        assert_eq!(stats.reasons["notice:too-many-requests-try-again"], 2);
+        assert_eq!(stats.reasons["notice:something-went-wrong-at-the"], 1);
+        assert!(stats.top_reasons(2).contains("ok:rate-limited: 2"));
+
+        for i in 0..MAX_REASONS * 2 {
+            stats.add_reason("notice", &format!("reason{}: text", i));
+        }
+        // the other reason is added beyond the cap
+        assert_eq!(stats.reasons.len(), MAX_REASONS + 1);
+        assert!(stats.reasons["notice:other"] > 0);
+    }
 }
@@ -33,25 +33,36 @@ enum Commands {
     Req(req::ReqOpts),
 }
 
+/// Exit code when the benchmark is interrupted by ctrl-c
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 fn main() {
     let args = Cli::parse();
     println!("{:?}", args);
-    match args.command {
+    let result = match args.command {
         Commands::Connect(opts) => {
             let rt = runtime::get_rt(opts.threads);
-            rt.block_on(connect::start(opts.clone()));
+            rt.block_on(connect::start(opts.clone()))
         }
         Commands::Echo(opts) => {
             let rt = runtime::get_rt(opts.threads);
-            rt.block_on(echo::start(opts.clone()));
+            rt.block_on(echo::start(opts.clone()))
         }
         Commands::Event(opts) => {
             let rt = runtime::get_rt(opts.threads);
-            rt.block_on(event::start(opts.clone()));
+            rt.block_on(event::start(opts.clone()))
         }
         Commands::Req(opts) => {
             let rt = runtime::get_rt(opts.threads);
-            rt.block_on(req::start(opts.clone()));
+            rt.block_on(req::start(opts.clone()))
+        }
+    };
+    match result {
+        Ok(result) if result.interrupted => std::process::exit(INTERRUPTED_EXIT_CODE),
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
     }
 }
@@ -1,14 +1,17 @@
+use crate::filter::{parse_filter, FilterTemplate};
+use crate::stream::WsStream;
 use crate::util::{gen_close, gen_req, parse_interface};
-use crate::{add1, bench_message, BenchOpts, Error, MessageStats};
+use crate::{
+    add1, bench_message, close, BenchOpts, BenchResult, ConnectionOpts, Error, MessageStats,
+    Shutdown,
+};
 use clap::Parser;
 use futures_util::{SinkExt, StreamExt};
 use parking_lot::Mutex;
-use tokio_tungstenite::MaybeTlsStream;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
 use tokio::{time, time::Duration};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
 use url::Url;
 
 /// Event benchmark options
@@ -34,21 +37,36 @@ pub struct ReqOpts {
     #[arg(short = 't', long, default_value = "0", value_name = "NUM")]
     pub threads: usize,
 
+    /// Stop the benchmark after second, ignore when set to 0
+    #[arg(short = 'd', long, default_value = "0", value_name = "NUM")]
+    pub duration: u64,
+
+    /// Exclude the first seconds from measurements, ignore when set to 0
+    #[arg(short = 'w', long, default_value = "0", value_name = "NUM")]
+    pub warmup: u64,
+
     /// Network interface address list
     #[arg(short = 'i', long, value_name = "IP", value_parser = parse_interface)]
     pub interface: Option<Vec<SocketAddr>>,
 
+    #[command(flatten)]
+    pub connection: ConnectionOpts,
+
     /// Request filter limit
     #[arg(long, default_value = "1", value_name = "NUM")]
     pub limit: usize,
 
+    /// Request filter template as json or file path, a filter or an array of filters, placeholders {id} {pubkey} {hashtag} {now} {now-N} are replaced with random values
+    #[arg(long, value_name = "JSON|FILE", value_parser = parse_filter, conflicts_with = "limit")]
+    pub filter: Option<FilterTemplate>,
+
     /// Display stats information as json, time format as milli seconds
     #[arg(long)]
     pub json: bool,
 }
 
 /// Start bench
-pub async fn start(opts: ReqOpts) {
+pub async fn start(opts: ReqOpts) -> Result<BenchResult, Error> {
     let bench_opts = BenchOpts {
         url: opts.url,
         count: opts.count,
@@ -56,6 +74,9 @@ pub async fn start(opts: ReqOpts) {
         keepalive: opts.keepalive,
         threads: opts.threads,
         interface: opts.interface,
+        duration: opts.duration,
+        warmup: opts.warmup,
+        connection: opts.connection,
     };
     let event_stats = Arc::new(Mutex::new(MessageStats {
         total: 0,
@@ -63,39 +84,60 @@ pub async fn start(opts: ReqOpts) {
     }));
     let c_stats = event_stats.clone();
     let limit = opts.limit;
+    let filter = opts.filter;
 
-    bench_message(bench_opts, event_stats, opts.json, move |stream| {
-        loop_req(stream, c_stats, limit)
-    })
-    .await;
+    bench_message(
+        bench_opts,
+        event_stats,
+        opts.json,
+        move |stream, shutdown| loop_req(stream, c_stats, limit, filter, shutdown),
+    )
+    .await
 }
 
 /// Loop request event
 pub async fn loop_req(
-    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    stream: WsStream,
     stats: Arc<Mutex<MessageStats>>,
     limit: usize,
+    filter: Option<FilterTemplate>,
+    mut shutdown: Shutdown,
 ) -> Result<(), Error> {
     let (mut write, mut read) = stream.split();
+    let gen = || match &filter {
+        Some(filter) => filter.gen_req(None),
+        None => gen_req(None, None, limit),
+    };
     // wait connect success
     time::sleep(Duration::from_secs(1)).await;
     let mut start = time::Instant::now();
-    add1!(stats, total);
-    let req = gen_req(None, None, limit);
+    let mut closing = false;
+    let req = gen();
+    {
+        let mut r = stats.lock();
+        r.total += 1;
+        r.add_sent(req.len());
+    }
     // println!("req {}", req);
     write.send(Message::Text(req)).await?;
     loop {
-        let msg = read.next().await;
+        let msg = tokio::select! {
+            msg = read.next() => msg,
+            _ = shutdown.wait(), if !closing => {
+                // stop after the in-flight request
+                closing = true;
+                continue;
+            }
+        };
         // println!("message {:?}", msg);
         match msg {
             Some(msg) => {
                 let msg = msg?;
                 if msg.is_text() {
-                    let req = gen_req(None, None, limit);
                     let msg = msg.to_string();
                     {
                         let mut r = stats.lock();
-                        r.size += msg.len() + req.len();
+                        r.add_received(msg.len());
                     }
                     if msg.contains("EVENT") {
                         add1!(stats, event);
@@ -103,16 +145,26 @@ pub async fn loop_req(
                     if msg.contains("EOSE") {
                         {
                             let mut r = stats.lock();
-                            r.success_time = r.success_time.add(start.elapsed());
+                            r.add_complete(start);
+                        }
+                        let close_req = gen_close(None);
+                        stats.lock().add_sent(close_req.len());
+                        write.send(Message::Text(close_req)).await?;
+                        if closing {
+                            return close(&mut write, &mut read).await;
+                        }
+                        let req = gen();
+                        {
+                            let mut r = stats.lock();
+                            r.total += 1;
+                            r.add_sent(req.len());
                         }
-                        add1!(stats, complete, total);
-                        write.send(Message::Text(gen_close(None))).await?;
                         start = time::Instant::now();
                         // send again
                         write.send(Message::Text(req)).await?;
                     }
-                } else if msg.is_close() {
-                    break;
+                } else if let Message::Close(frame) = msg {
+                    return Err(Error::Closed(frame));
                 }
             }
             None => break,
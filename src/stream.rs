@@ -0,0 +1,145 @@
+use crate::deflate::DeflateStream;
+use crate::CompressionStats;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Websocket stream of the benchmark connections
+pub type WsStream = WebSocketStream<DeflateStream<MaybeTlsStream<CountingStream<TcpStream>>>>;
+
+/// Bytes sent and received at tcp level, include proxy, tls and websocket framing,
+/// and the message payload bytes of the connections negotiated permessage-deflate
+#[derive(Debug, Default)]
+pub struct Traffic {
+    sent: AtomicUsize,
+    received: AtomicUsize,
+    payload_sent: AtomicUsize,
+    compressed_sent: AtomicUsize,
+    payload_received: AtomicUsize,
+    compressed_received: AtomicUsize,
+}
+
+impl Traffic {
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Count the message payload bytes sent, after and before compression
+    pub fn add_compressed_sent(&self, compressed: usize, payload: usize) {
+        self.compressed_sent
+            .fetch_add(compressed, Ordering::Relaxed);
+        self.payload_sent.fetch_add(payload, Ordering::Relaxed);
+    }
+
+    /// Count the message payload bytes received, before and after decompression
+    pub fn add_compressed_received(&self, compressed: usize, payload: usize) {
+        self.compressed_received
+            .fetch_add(compressed, Ordering::Relaxed);
+        self.payload_received.fetch_add(payload, Ordering::Relaxed);
+    }
+
+    /// Message payload bytes of the compressed connections
+    pub fn compression(&self) -> CompressionStats {
+        CompressionStats {
+            sent: self.payload_sent.load(Ordering::Relaxed),
+            compressed_sent: self.compressed_sent.load(Ordering::Relaxed),
+            received: self.payload_received.load(Ordering::Relaxed),
+            compressed_received: self.compressed_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Stream wrapper counting the bytes read and written
+#[derive(Debug)]
+pub struct CountingStream<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S, traffic: Arc<Traffic>) -> Self {
+        Self { inner, traffic }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let len = buf.filled().len() - filled;
+        self.traffic.received.fetch_add(len, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.traffic.sent.fetch_add(len, Ordering::Relaxed);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let len = ready!(Pin::new(&mut self.inner).poll_write_vectored(cx, bufs))?;
+        self.traffic.sent.fetch_add(len, Ordering::Relaxed);
+        Poll::Ready(Ok(len))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CountingStream, Traffic};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn counting() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let traffic = Arc::new(Traffic::default());
+            let (client, mut server) = tokio::io::duplex(64);
+            let mut client = CountingStream::new(client, traffic.clone());
+            client.write_all(b"hello").await.unwrap();
+            server.write_all(b"hi").await.unwrap();
+            let mut buf = [0; 2];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(traffic.sent(), 5);
+            assert_eq!(traffic.received(), 2);
+        });
+    }
+}
@@ -0,0 +1,285 @@
+use crate::ConnectionOpts;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use url::Url;
+
+/// TLS connector shared by all connections
+#[derive(Clone)]
+pub struct Tls {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl Tls {
+    /// Build from options, return None when the url is not wss
+    pub fn new(opts: &ConnectionOpts, url: &Url) -> io::Result<Option<Self>> {
+        if url.scheme() != "wss" {
+            return Ok(None);
+        }
+        let name = match (&opts.sni, url.host_str()) {
+            (Some(sni), _) => sni.as_str(),
+            (None, Some(host)) => host.trim_start_matches('[').trim_end_matches(']'),
+            (None, None) => "",
+        };
+        let server_name = ServerName::try_from(name)
+            .map_err(|_| invalid(format!("invalid server name: {:?}", name)))?;
+        Ok(Some(Self {
+            connector: TlsConnector::from(client_config(opts)?),
+            server_name,
+        }))
+    }
+
+    /// TLS handshake over the tcp stream
+    pub async fn connect<S>(&self, tcp: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connector.connect(self.server_name.clone(), tcp).await
+    }
+}
+
+/// Build rustls client config with webpki roots and extra CA, client certificate
+pub fn client_config(opts: &ConnectionOpts) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(path) = &opts.cacert {
+        let certs = read_certs(path)?;
+        let (added, _) = roots.add_parsable_certificates(&certs);
+        if added == 0 {
+            return Err(invalid(format!("no CA certificate in {}", path.display())));
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let mut config = match (&opts.cert, &opts.key) {
+        (Some(cert), Some(key)) => {
+            let certs = read_certs(cert)?.into_iter().map(Certificate).collect();
+            builder
+                .with_single_cert(certs, read_key(key)?)
+                .map_err(|e| invalid(e.to_string()))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(invalid("both --cert and --key are required".to_owned())),
+    };
+    if opts.insecure {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerifier));
+    }
+    Ok(Arc::new(config))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Read all certificates from PEM file
+fn read_certs(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Read the first private key from PEM file
+fn read_key(path: &Path) -> io::Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut open(path)?)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid(format!("no private key in {}", path.display())))
+}
+
+/// Accept any server certificate, for lab use only
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_certs, read_key, Tls};
+    use crate::ConnectionOpts;
+    use rustls::{Certificate, ServerConfig, ServerName};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio_rustls::TlsAcceptor;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    fn server_name(opts: &ConnectionOpts, url: &str) -> Option<ServerName> {
+        Tls::new(opts, &url.parse().unwrap())
+            .unwrap()
+            .map(|tls| tls.server_name)
+    }
+
+    #[test]
+    fn options() {
+        let bad = std::env::temp_dir().join(format!("nostr-bench-bad-{}.pem", std::process::id()));
+        std::fs::write(&bad, "-----BEGIN CERTIFICATE-----\nbad\n").unwrap();
+        let url = "wss://relay.test".parse().unwrap();
+        let cacert = |path: PathBuf| ConnectionOpts {
+            cacert: Some(path),
+            ..Default::default()
+        };
+        assert!(Tls::new(&cacert(fixture("ca.pem")), &url).is_ok());
+        assert!(Tls::new(&cacert(bad.clone()), &url).is_err());
+        assert!(Tls::new(&cacert(fixture("missing.pem")), &url).is_err());
+        assert!(Tls::new(&cacert(fixture("server.key")), &url).is_err());
+
+        let client = |cert: Option<PathBuf>, key: Option<PathBuf>| ConnectionOpts {
+            cert,
+            key,
+            ..Default::default()
+        };
+        let (cert, key) = (fixture("server.pem"), fixture("server.key"));
+        assert!(Tls::new(&client(Some(cert.clone()), Some(key.clone())), &url).is_ok());
+        assert!(Tls::new(&client(Some(cert.clone()), None), &url).is_err());
+        assert!(Tls::new(&client(None, Some(key)), &url).is_err());
+        assert!(Tls::new(&client(Some(cert.clone()), Some(cert)), &url).is_err());
+        assert!(Tls::new(
+            &client(Some(fixture("server.pem")), Some(bad.clone())),
+            &url
+        )
+        .is_err());
+        std::fs::remove_file(bad).unwrap();
+
+        // sni overrides the url host, ipv6 host is without brackets
+        let opts = ConnectionOpts::default();
+        let sni = ConnectionOpts {
+            sni: Some("relay.test".to_owned()),
+            ..Default::default()
+        };
+        let relay = ServerName::try_from("relay.test").unwrap();
+        assert_eq!(
+            server_name(&sni, "wss://127.0.0.1:8443"),
+            Some(relay.clone())
+        );
+        assert_eq!(server_name(&opts, "wss://relay.test/ws"), Some(relay));
+        assert_eq!(
+            server_name(&opts, "wss://[::1]:8443"),
+            Some(ServerName::try_from("::1").unwrap())
+        );
+        assert_eq!(server_name(&sni, "ws://127.0.0.1:8080"), None);
+        let invalid = ConnectionOpts {
+            sni: Some("bad name".to_owned()),
+            ..Default::default()
+        };
+        assert!(Tls::new(&invalid, &url).is_err());
+    }
+
+    #[test]
+    fn handshake() {
+        let certs = read_certs(&fixture("server.pem"))
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, read_key(&fixture("server.key")).unwrap())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        // return the sni received by the server, none when the handshake failed
+        let connect = |opts: ConnectionOpts| {
+            let acceptor = acceptor.clone();
+            rt.block_on(async move {
+                let tls = Tls::new(&opts, &"wss://127.0.0.1:8443".parse().unwrap())
+                    .unwrap()
+                    .unwrap();
+                let (client, server) = tokio::io::duplex(16 * 1024);
+                let server = tokio::spawn(async move { acceptor.accept(server).await });
+                let client = tls.connect(client).await;
+                let server = server.await.unwrap();
+                match (client, server) {
+                    (Ok(_), Ok(server)) => {
+                        Some(server.get_ref().1.sni_hostname().map(str::to_owned))
+                    }
+                    _ => None,
+                }
+            })
+        };
+
+        // self-signed CA is not trusted by default
+        assert_eq!(
+            connect(ConnectionOpts {
+                sni: Some("relay.test".to_owned()),
+                ..Default::default()
+            }),
+            None
+        );
+        assert_eq!(
+            connect(ConnectionOpts {
+                cacert: Some(fixture("ca.pem")),
+                sni: Some("relay.test".to_owned()),
+                ..Default::default()
+            }),
+            Some(Some("relay.test".to_owned()))
+        );
+        // the certificate is not valid for the name
+        assert_eq!(
+            connect(ConnectionOpts {
+                cacert: Some(fixture("ca.pem")),
+                sni: Some("other.test".to_owned()),
+                ..Default::default()
+            }),
+            None
+        );
+        assert_eq!(
+            connect(ConnectionOpts {
+                insecure: true,
+                sni: Some("other.test".to_owned()),
+                ..Default::default()
+            }),
+            Some(Some("other.test".to_owned()))
+        );
+    }
+}
@@ -3,21 +3,216 @@ use nostr::prelude::{
     rand::distributions::{Alphanumeric, DistString},
     rand::Rng,
 };
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio_tungstenite::tungstenite::http::{
+    header::SEC_WEBSOCKET_EXTENSIONS, HeaderName, HeaderValue,
+};
 use url::Url;
 
-/// Parse interface string
+/// Parse interface string, accept both ipv4 and ipv6 address
 pub fn parse_interface(s: &str) -> Result<SocketAddr, String> {
-    Ok(format!("{}:0", s).parse().map_err(|_| "error format")?)
+    let ip: IpAddr = s
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| "error format")?;
+    Ok(SocketAddr::new(ip, 0))
+}
+
+/// Network interface address list grouped by address family
+#[derive(Debug, Clone, Default)]
+pub struct Interfaces {
+    v4: Vec<SocketAddr>,
+    v6: Vec<SocketAddr>,
 }
 
-pub fn parse_wsaddr(url: &Url) -> std::io::Result<SocketAddr> {
-    let addrs = url.socket_addrs(|| match url.scheme() {
-        "wss" => Some(443),
-        "ws" => Some(80),
-        _ => None,
+impl Interfaces {
+    pub fn new(interfaces: Vec<SocketAddr>) -> Self {
+        let (v4, v6) = interfaces.into_iter().partition(|addr| addr.is_ipv4());
+        Self { v4, v6 }
+    }
+
+    /// Pick the i-th interface in round robin with the same address family as the target,
+    /// return None when no interface specified
+    pub fn get(&self, target: &SocketAddr, i: usize) -> std::io::Result<Option<SocketAddr>> {
+        if self.v4.is_empty() && self.v6.is_empty() {
+            return Ok(None);
+        }
+        let list = if target.is_ipv4() { &self.v4 } else { &self.v6 };
+        if list.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!("no interface address for target {}", target),
+            ));
+        }
+        Ok(Some(list[i % list.len()]))
+    }
+}
+
+pub async fn parse_wsaddr(url: &Url) -> std::io::Result<SocketAddr> {
+    Ok(resolve_wsaddrs(url, &[]).await?[0])
+}
+
+/// Resolve all addresses of the url, use the override list first
+pub async fn resolve_wsaddrs(url: &Url, overrides: &[Resolve]) -> std::io::Result<Vec<SocketAddr>> {
+    let port = match url.port_or_known_default() {
+        Some(port) => port,
+        None => match url.scheme() {
+            "wss" => 443,
+            "ws" => 80,
+            _ => 0,
+        },
+    };
+    let host = url.host_str().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("no host in {}", url),
+        )
     })?;
-    Ok(addrs[0])
+    // ipv6 host is in brackets in url
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    // the url host is lowercased, match the override host case-insensitively as curl
+    if let Some(item) = overrides
+        .iter()
+        .find(|r| r.host.eq_ignore_ascii_case(host) && r.port == port)
+    {
+        return Ok(item
+            .addrs
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect());
+    }
+    // resolve without blocking the runtime worker
+    let addrs = lookup_host((host, port)).await?.collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no address for {}", url),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Custom address for host and port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolve {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// Parse resolve string as curl, format `host:port:addr[,addr]...`, ipv6 host and address can be in brackets
+pub fn parse_resolve(s: &str) -> Result<Resolve, String> {
+    let format = "error format, expect host:port:addr";
+    // split from the left, the addresses may contain colons
+    let (host, rest) = match s.strip_prefix('[') {
+        Some(s) => {
+            let (host, rest) = s.split_once(']').ok_or(format)?;
+            (host, rest.strip_prefix(':').ok_or(format)?)
+        }
+        None => s.split_once(':').ok_or(format)?,
+    };
+    let (port, addrs) = rest.split_once(':').ok_or(format)?;
+    if host.is_empty() || addrs.is_empty() {
+        return Err(format.to_owned());
+    }
+    let port = port.parse().map_err(|_| "error port")?;
+    let addrs = addrs
+        .split(',')
+        .map(|addr| {
+            let ip = addr
+                .strip_prefix('[')
+                .and_then(|addr| addr.strip_suffix(']'))
+                .unwrap_or(addr);
+            ip.parse::<IpAddr>()
+                .map_err(|_| format!("error address {}", addr))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Resolve {
+        host: host.to_owned(),
+        port,
+        addrs,
+    })
+}
+
+/// Parse http header, format `Name: value`
+pub fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or("error format, expect name: value")?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| "error header name")?;
+    // only the extension offered by --deflate can be handled
+    if name == SEC_WEBSOCKET_EXTENSIONS {
+        return Err("use --deflate to offer permessage-deflate".to_owned());
+    }
+    let mut value = HeaderValue::from_str(value.trim()).map_err(|_| "error header value")?;
+    // values such as tokens are hidden from the printed options
+    value.set_sensitive(true);
+    Ok((name, value))
+}
+
+/// How to distribute connections across the resolved addresses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Balance {
+    /// Only use the first address
+    #[default]
+    First,
+    /// Use every address in turn
+    RoundRobin,
+    /// Use a random address
+    Random,
+}
+
+impl Balance {
+    /// Pick address for the i-th connection
+    pub fn pick(&self, addrs: &[SocketAddr], i: usize) -> SocketAddr {
+        match self {
+            Balance::First => addrs[0],
+            Balance::RoundRobin => addrs[i % addrs.len()],
+            Balance::Random => addrs[rand::thread_rng().gen_range(0..addrs.len())],
+        }
+    }
+}
+
+/// Exponential backoff delay with random jitter
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    jitter: u8,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Delay starts from min and doubles until max, randomly reduced by up to jitter percent
+    pub fn new(min: Duration, max: Duration, jitter: u8) -> Self {
+        Self {
+            min,
+            max,
+            jitter: jitter.min(100),
+            attempt: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delay of the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        if self.jitter == 0 {
+            return delay;
+        }
+        let ratio = rand::thread_rng().gen_range(0.0..=self.jitter as f64 / 100.0);
+        delay.mul_f64(1.0 - ratio)
+    }
 }
 
 /// Generate random hashtag between nostr-bench-0 to nostr-bench-1000
@@ -31,7 +226,7 @@ pub fn gen_hashtag() -> String {
 /// Generate request
 pub fn gen_req(id: Option<String>, tag: Option<String>, limit: usize) -> String {
     let id = id.unwrap_or("sub".to_owned());
-    let tag = tag.unwrap_or_else(|| gen_hashtag());
+    let tag = tag.unwrap_or_else(gen_hashtag);
     format!(
         "[\"REQ\", \"{}\", {{\"#t\": [\"{}\"], \"limit\": {}}}]",
         id, tag, limit
@@ -44,8 +239,8 @@ pub fn gen_close(id: Option<String>) -> String {
     format!("[\"CLOSE\", \"{}\"]", id)
 }
 
-/// Generate random note with different key
-pub fn gen_note_event<T: Into<String>>(content: T) -> String {
+/// Generate random note event with different key
+pub fn gen_note<T: Into<String>>(content: T) -> nostr::Event {
     let key = nostr::Keys::generate();
     let tags = vec![
         nostr::Tag::PubKey(key.public_key(), None),
@@ -61,8 +256,12 @@ pub fn gen_note_event<T: Into<String>>(content: T) -> String {
         nostr::Tag::Hashtag(gen_hashtag()),
     ];
     let builder = nostr::EventBuilder::new_text_note(content, &tags);
-    let event = builder.to_event(&key).unwrap();
-    nostr::ClientMessage::new_event(event).as_json()
+    builder.to_event(&key).unwrap()
+}
+
+/// Generate random note with different key
+pub fn gen_note_event<T: Into<String>>(content: T) -> String {
+    nostr::ClientMessage::new_event(gen_note(content)).as_json()
 }
 
 pub fn gen_string(size: usize) -> String {
@@ -73,7 +272,12 @@ pub fn gen_string(size: usize) -> String {
 mod tests {
     use crate::util::gen_string;
 
-    use super::{gen_close, gen_req};
+    use super::{
+        gen_close, gen_req, parse_header, parse_interface, parse_resolve, resolve_wsaddrs, Backoff,
+        Interfaces,
+    };
+    use std::net::IpAddr;
+    use std::time::Duration;
     #[test]
     fn generate() {
         assert_eq!(
@@ -83,4 +287,105 @@ mod tests {
         assert_eq!(gen_close(Some("id".to_owned())), r#"["CLOSE", "id"]"#);
         assert_eq!(gen_string(10).len(), 10);
     }
+
+    #[test]
+    fn interface() {
+        let v4 = parse_interface("192.168.0.2").unwrap();
+        let v6 = parse_interface("::1").unwrap();
+        assert_eq!(v4.to_string(), "192.168.0.2:0");
+        assert_eq!(v6.to_string(), "[::1]:0");
+        assert_eq!(
+            parse_interface("[fe80::1]").unwrap().to_string(),
+            "[fe80::1]:0"
+        );
+        assert!(parse_interface("localhost").is_err());
+
+        let target4 = "127.0.0.1:80".parse().unwrap();
+        let target6 = "[::1]:80".parse().unwrap();
+        assert_eq!(Interfaces::default().get(&target6, 0).unwrap(), None);
+        let interfaces = Interfaces::new(vec![v4, v6]);
+        assert_eq!(interfaces.get(&target4, 1).unwrap(), Some(v4));
+        assert_eq!(interfaces.get(&target6, 1).unwrap(), Some(v6));
+        assert!(Interfaces::new(vec![v4]).get(&target6, 0).is_err());
+    }
+
+    #[test]
+    fn resolve() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let resolve = parse_resolve("relay.example.com:443:127.0.0.1,[::1]").unwrap();
+        assert_eq!(resolve.host, "relay.example.com");
+        assert_eq!(resolve.port, 443);
+        assert_eq!(resolve.addrs.len(), 2);
+        assert!(parse_resolve("relay.example.com:443").is_err());
+        assert!(parse_resolve("relay.example.com:443:").is_err());
+        assert!(parse_resolve("relay.example.com:port:127.0.0.1").is_err());
+        assert!(parse_resolve("relay.example.com:443:[::1").is_err());
+
+        let v6 = parse_resolve("relay.example.com:443:::1,[fe80::1]").unwrap();
+        assert_eq!(v6.port, 443);
+        assert_eq!(
+            v6.addrs,
+            vec!["::1".parse::<IpAddr>().unwrap(), "fe80::1".parse().unwrap()]
+        );
+        let host6 = parse_resolve("[::1]:8080:127.0.0.1").unwrap();
+        assert_eq!(host6.host, "::1");
+        assert_eq!(host6.port, 8080);
+        assert!(parse_resolve("[::1:8080:127.0.0.1").is_err());
+        let url = "ws://[::1]:8080".parse().unwrap();
+        assert_eq!(
+            rt.block_on(resolve_wsaddrs(&url, &[host6])).unwrap()[0].to_string(),
+            "127.0.0.1:8080"
+        );
+
+        let url = "wss://relay.example.com".parse().unwrap();
+        let addrs = rt.block_on(resolve_wsaddrs(&url, &[resolve])).unwrap();
+        assert_eq!(addrs[0].to_string(), "127.0.0.1:443");
+        assert_eq!(addrs[1].to_string(), "[::1]:443");
+        let upper = parse_resolve("Relay.Example.COM:443:10.0.0.1").unwrap();
+        let addrs = rt.block_on(resolve_wsaddrs(&url, &[upper])).unwrap();
+        assert_eq!(addrs[0].to_string(), "10.0.0.1:443");
+        let url = "ws://127.0.0.1:8080".parse().unwrap();
+        assert_eq!(
+            rt.block_on(resolve_wsaddrs(&url, &[])).unwrap()[0].to_string(),
+            "127.0.0.1:8080"
+        );
+        let url = "ws://[::1]:8080".parse().unwrap();
+        assert_eq!(
+            rt.block_on(resolve_wsaddrs(&url, &[])).unwrap()[0].to_string(),
+            "[::1]:8080"
+        );
+    }
+
+    #[test]
+    fn header() {
+        let (name, value) = parse_header("X-Api-Key:  secret ").unwrap();
+        assert_eq!(name.as_str(), "x-api-key");
+        assert_eq!(value, "secret");
+        let (name, value) = parse_header("Authorization: Bearer a:b").unwrap();
+        assert_eq!(name.as_str(), "authorization");
+        assert_eq!(value, "Bearer a:b");
+        assert!(value.is_sensitive());
+        assert!(!format!("{:?}", value).contains("Bearer"));
+        assert!(parse_header("X-Api-Key").is_err());
+        assert!(parse_header("Bad Name: value").is_err());
+        assert!(parse_header("Sec-WebSocket-Extensions: permessage-deflate").is_err());
+    }
+
+    #[test]
+    fn backoff() {
+        let ms = Duration::from_millis;
+        let mut backoff = Backoff::new(ms(100), ms(500), 0);
+        let delays = (0..5).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![ms(100), ms(200), ms(400), ms(500), ms(500)]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), ms(100));
+
+        let mut backoff = Backoff::new(ms(1000), ms(1000), 50);
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= ms(500) && delay <= ms(1000));
+        }
+    }
 }